@@ -0,0 +1,5 @@
+// Breaks whatever is in front of the robot and moves into its place
+.macro mine_forward
+brk
+fwd
+.endm
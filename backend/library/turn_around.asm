@@ -0,0 +1,5 @@
+// Turns the robot to face the way it came
+.macro turn_around
+rot
+rot
+.endm
@@ -1,11 +1,25 @@
-use crate::asm::AssemblyLineParseError;
+use crate::asm::{AssemblingError, AssemblyLineParseError};
+use crate::lint::Lint;
+use crate::robot::Fault;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "t", content = "d")]
 pub enum Request {
+    /// Replace the robot's BIOS
     #[serde(rename = "u")]
     UploadCode(String),
+    /// Replace the program the BIOS runs with `exec`
+    #[serde(rename = "p")]
+    UploadProgram(String),
+    /// Choose which part of the world to receive map frames for
+    #[serde(rename = "v")]
+    SetViewport {
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+    },
 }
 
 #[derive(Debug, Serialize)]
@@ -15,11 +29,58 @@ pub enum Response {
     UploadCode {
         success: bool,
         errors: Option<Vec<CodeError>>,
+        /// Everything that went wrong assembling the parsed code
+        assembly_errors: Vec<AssemblingError>,
+        /// Likely mistakes that don't stop the code from being uploaded
+        warnings: Vec<CodeWarning>,
+        /// What actually ended up in the robot's BIOS or program memory, if the upload succeeded
+        disassembly: Option<String>,
+    },
+    /// The player's robot hit a fault, and stopped unless the BIOS fault handler took over
+    #[serde(rename = "f")]
+    RobotFault {
+        fault: Fault,
+        /// Whether the BIOS fault handler took over
+        handled: bool,
+        /// Whether the faulting instruction is in program memory rather than the BIOS
+        in_program: bool,
+        /// Address of the faulting instruction
+        address: usize,
+        /// The faulting instruction disassembled, unless the address is past the end of memory
+        instruction: Option<String>,
     },
 }
 
 #[derive(Debug, Serialize)]
 pub struct CodeError {
+    /// The library snippet the error is in, or None if it's in the uploaded code
+    pub file: Option<String>,
+    /// Line of the error, counted from the start of its file
     pub line: usize,
+    /// Column of the first character of the offending token
+    pub start: usize,
+    /// Column just past the last character of the offending token
+    pub end: usize,
+    pub severity: Severity,
+    /// Description of the problem for the player
+    pub message: String,
+    /// A possible fix, like "did you mean %rga?"
+    pub suggestion: Option<String>,
     pub error: AssemblyLineParseError,
 }
+
+#[derive(Debug, Serialize)]
+pub struct CodeWarning {
+    pub line: usize,
+    pub severity: Severity,
+    /// Description of the problem for the player
+    pub message: String,
+    pub warning: Lint,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
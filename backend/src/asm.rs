@@ -1,9 +1,10 @@
-use crate::api::CodeError;
+use crate::api::{CodeError, Severity};
+use crate::library::Library;
 use crate::robot::{BIOS_MEM_SIZE, DATA_MEM_SIZE};
 use log::debug;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::{self, Read};
 use std::str::FromStr;
@@ -39,23 +40,74 @@ const OP_INVENTORYDROP: u8 = 20;
 const OP_INVENTORYITEM: u8 = 21;
 const OP_OUTPUT: u8 = 22;
 const OP_NOOP: u8 = 23;
+const OP_CALL: u8 = 24;
+const OP_CMP: u8 = 25;
+const OP_JZ: u8 = 26;
+const OP_JNZ: u8 = 27;
+const OP_JC: u8 = 28;
+const OP_JNC: u8 = 29;
+const OP_JN: u8 = 30;
+const OP_JNN: u8 = 31;
+const OP_LOAD: u8 = 32;
+const OP_STORE: u8 = 33;
+const OP_AND: u8 = 34;
+const OP_OR: u8 = 35;
+const OP_XOR: u8 = 36;
+const OP_NOT: u8 = 37;
+const OP_SHL: u8 = 38;
+const OP_SHR: u8 = 39;
+const OP_MOD: u8 = 40;
+const OP_TRAP: u8 = 41;
 
+// Arithmetic opcodes with these bits set take explicit operands, so every other opcode must stay
+// below 64
+const OPERAND_MODES: u8 = 0xc0;
+/// Mode bits for arithmetic on two registers
+const MODE_REG: u8 = 0x80;
+/// Mode bits for arithmetic on a register and an immediate
+const MODE_IMM: u8 = 0x40;
+
+/// Length of the longest encoded instruction
+const MAX_OP_LEN: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum OpCode<L> {
     Add,
     Sub,
     Mul,
     Div,
-    Jmp { label: L },
-    JmpCondition { label: L },
-    MovReg { to: Register, from: Register },
-    MovImm { to: Register, from: u8 },
-    MovAddr { to: Register, from: L },
-    StackGet,
-    StackSet,
+    Jmp {
+        label: L,
+    },
+    JmpCondition {
+        label: L,
+    },
+    MovReg {
+        to: Register,
+        from: Register,
+    },
+    MovImm {
+        to: Register,
+        from: u8,
+    },
+    MovAddr {
+        to: Register,
+        from: L,
+    },
+    StackGet {
+        to: Register,
+    },
+    StackSet {
+        from: Register,
+    },
     Exec,
     Return,
-    CmpCallAddr { component: L },
-    CmpCallImm { component: u8 },
+    CmpCallAddr {
+        component: L,
+    },
+    CmpCallImm {
+        component: u8,
+    },
     Forward,
     Rotate,
     Break,
@@ -65,6 +117,251 @@ pub enum OpCode<L> {
     InventoryItem,
     Output,
     Noop,
+    Call {
+        label: L,
+    },
+    Cmp,
+    JmpIf {
+        condition: Condition,
+        label: L,
+    },
+    Load {
+        to: Register,
+    },
+    Store {
+        from: Register,
+    },
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Mod,
+    Arithmetic {
+        op: ArithOp,
+        lhs: Register,
+        rhs: Operand,
+    },
+    Trap {
+        label: L,
+    },
+}
+
+impl<L> OpCode<L> {
+    /// The instruction as written in assembly, without its operands
+    pub fn mnemonic(&self) -> &'static str {
+        use OpCode::*;
+        match self {
+            Add => "add",
+            Sub => "sub",
+            Mul => "mul",
+            Div => "div",
+            Jmp { .. } => "jmp",
+            JmpCondition { .. } => "jmpc",
+            MovReg { .. } | MovImm { .. } | MovAddr { .. } => "mov",
+            StackGet { .. } => "stac_g",
+            StackSet { .. } => "stac_s",
+            Exec => "exec",
+            Return => "return",
+            CmpCallAddr { .. } | CmpCallImm { .. } => "cmp_call",
+            Forward => "fwd",
+            Rotate => "rot",
+            Break => "brk",
+            Battery => "bttry",
+            InventoryGet => "inven",
+            InventoryDrop => "drop",
+            InventoryItem => "item",
+            Output => "out",
+            Noop => "noop",
+            Call { .. } => "call",
+            Cmp => "cmp",
+            JmpIf { condition, .. } => condition.mnemonic(),
+            Load { .. } => "load",
+            Store { .. } => "store",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            Not => "not",
+            Shl => "shl",
+            Shr => "shr",
+            Mod => "mod",
+            Arithmetic { op, .. } => op.mnemonic(),
+            Trap { .. } => "trap",
+        }
+    }
+
+    /// Rewrites operand-less arithmetic as the `%rga %rgb` form it's shorthand for
+    pub fn expand_shorthand(self) -> Self {
+        use OpCode::*;
+        let op = match self {
+            Add => ArithOp::Add,
+            Sub => ArithOp::Sub,
+            Mul => ArithOp::Mul,
+            Div => ArithOp::Div,
+            Mod => ArithOp::Mod,
+            And => ArithOp::And,
+            Or => ArithOp::Or,
+            Xor => ArithOp::Xor,
+            Shl => ArithOp::Shl,
+            Shr => ArithOp::Shr,
+            Cmp => ArithOp::Cmp,
+            op => return op,
+        };
+        Arithmetic {
+            op,
+            lhs: Register::RGA,
+            rhs: Operand::Register(Register::RGB),
+        }
+    }
+}
+
+/// An arithmetic or bitwise instruction that can be given its operands explicitly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Cmp,
+}
+
+impl ArithOp {
+    const ALL: [ArithOp; 11] = [
+        ArithOp::Add,
+        ArithOp::Sub,
+        ArithOp::Mul,
+        ArithOp::Div,
+        ArithOp::Mod,
+        ArithOp::And,
+        ArithOp::Or,
+        ArithOp::Xor,
+        ArithOp::Shl,
+        ArithOp::Shr,
+        ArithOp::Cmp,
+    ];
+
+    /// The opcode of the operand-less form, which the mode bits are added to
+    fn opcode(self) -> u8 {
+        use ArithOp::*;
+        match self {
+            Add => OP_ADD,
+            Sub => OP_SUB,
+            Mul => OP_MUL,
+            Div => OP_DIV,
+            Mod => OP_MOD,
+            And => OP_AND,
+            Or => OP_OR,
+            Xor => OP_XOR,
+            Shl => OP_SHL,
+            Shr => OP_SHR,
+            Cmp => OP_CMP,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        use ArithOp::*;
+        match self {
+            Add => "add",
+            Sub => "sub",
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            Shl => "shl",
+            Shr => "shr",
+            Cmp => "cmp",
+        }
+    }
+
+    /// The operand-less form of the instruction
+    fn shorthand<L>(self) -> OpCode<L> {
+        use ArithOp::*;
+        match self {
+            Add => OpCode::Add,
+            Sub => OpCode::Sub,
+            Mul => OpCode::Mul,
+            Div => OpCode::Div,
+            Mod => OpCode::Mod,
+            And => OpCode::And,
+            Or => OpCode::Or,
+            Xor => OpCode::Xor,
+            Shl => OpCode::Shl,
+            Shr => OpCode::Shr,
+            Cmp => OpCode::Cmp,
+        }
+    }
+}
+
+/// The second operand of arithmetic with explicit operands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    Immediate(u8),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(register) => register.fmt(f),
+            Operand::Immediate(value) => value.fmt(f),
+        }
+    }
+}
+
+/// What a conditional jump checks in the flags register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Zero,
+    NotZero,
+    Carry,
+    NotCarry,
+    Negative,
+    NotNegative,
+}
+
+impl Condition {
+    /// Every condition, in the same order as their opcodes
+    const ALL: [Condition; 6] = [
+        Condition::Zero,
+        Condition::NotZero,
+        Condition::Carry,
+        Condition::NotCarry,
+        Condition::Negative,
+        Condition::NotNegative,
+    ];
+
+    fn opcode(self) -> u8 {
+        use Condition::*;
+        match self {
+            Zero => OP_JZ,
+            NotZero => OP_JNZ,
+            Carry => OP_JC,
+            NotCarry => OP_JNC,
+            Negative => OP_JN,
+            NotNegative => OP_JNN,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        use Condition::*;
+        match self {
+            Zero => "jz",
+            NotZero => "jnz",
+            Carry => "jc",
+            NotCarry => "jnc",
+            Negative => "jn",
+            NotNegative => "jnn",
+        }
+    }
 }
 
 impl OpCode<LabelString> {
@@ -81,12 +378,9 @@ impl OpCode<LabelString> {
             }
             MovReg { to, from } => (MovReg { to, from }, None),
             MovImm { to, from } => (MovImm { to, from }, None),
-            MovAddr { to, ref from } => (
-                MovAddr { to: to, from: 0 },
-                Some((false, 2, from.0.clone())),
-            ),
-            StackGet => (StackGet, None),
-            StackSet => (StackSet, None),
+            MovAddr { to, ref from } => (MovAddr { to, from: 0 }, Some((false, 2, from.0.clone()))),
+            StackGet { to } => (StackGet { to }, None),
+            StackSet { from } => (StackSet { from }, None),
             Exec => (Exec, None),
             Return => (Return, None),
             CmpCallAddr { ref component } => (
@@ -104,13 +398,35 @@ impl OpCode<LabelString> {
             InventoryItem => (InventoryItem, None),
             Output => (Output, None),
             Noop => (Noop, None),
-            _ => unimplemented!(),
+            Call { ref label } => (Call { label: 0 }, Some((true, 1, label.0.clone()))),
+            Cmp => (Cmp, None),
+            Load { to } => (Load { to }, None),
+            Store { from } => (Store { from }, None),
+            And => (And, None),
+            Or => (Or, None),
+            Xor => (Xor, None),
+            Not => (Not, None),
+            Shl => (Shl, None),
+            Shr => (Shr, None),
+            Mod => (Mod, None),
+            Arithmetic { op, lhs, rhs } => (Arithmetic { op, lhs, rhs }, None),
+            Trap { ref label } => (Trap { label: 0 }, Some((true, 1, label.0.clone()))),
+            JmpIf {
+                condition,
+                ref label,
+            } => (
+                JmpIf {
+                    condition,
+                    label: 0,
+                },
+                Some((true, 1, label.0.clone())),
+            ),
         }
     }
 }
 impl Read for OpCode<u8> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut op = [0u8; 3];
+        let mut op = [0u8; MAX_OP_LEN];
         use OpCode::*;
         let len = match &*self {
             Add => {
@@ -157,13 +473,15 @@ impl Read for OpCode<u8> {
                 op[2] = *from;
                 3
             }
-            StackGet => {
+            StackGet { to } => {
                 op[0] = OP_STACKGET;
-                1
+                op[1] = to.into();
+                2
             }
-            StackSet => {
+            StackSet { from } => {
                 op[0] = OP_STACKSET;
-                1
+                op[1] = from.into();
+                2
             }
             Exec => {
                 op[0] = OP_EXEC;
@@ -219,8 +537,83 @@ impl Read for OpCode<u8> {
                 op[0] = OP_NOOP;
                 1
             }
+            Call { label } => {
+                op[0] = OP_CALL;
+                op[1] = *label;
+                2
+            }
+            Cmp => {
+                op[0] = OP_CMP;
+                1
+            }
+            JmpIf { condition, label } => {
+                op[0] = condition.opcode();
+                op[1] = *label;
+                2
+            }
+            Load { to } => {
+                op[0] = OP_LOAD;
+                op[1] = to.into();
+                2
+            }
+            Store { from } => {
+                op[0] = OP_STORE;
+                op[1] = from.into();
+                2
+            }
+            And => {
+                op[0] = OP_AND;
+                1
+            }
+            Or => {
+                op[0] = OP_OR;
+                1
+            }
+            Xor => {
+                op[0] = OP_XOR;
+                1
+            }
+            Not => {
+                op[0] = OP_NOT;
+                1
+            }
+            Shl => {
+                op[0] = OP_SHL;
+                1
+            }
+            Shr => {
+                op[0] = OP_SHR;
+                1
+            }
+            Mod => {
+                op[0] = OP_MOD;
+                1
+            }
+            Arithmetic {
+                op: arith,
+                lhs,
+                rhs,
+            } => {
+                op[1] = lhs.into();
+                match rhs {
+                    Operand::Register(register) => {
+                        op[0] = arith.opcode() | MODE_REG;
+                        op[2] = register.into();
+                    }
+                    Operand::Immediate(value) => {
+                        op[0] = arith.opcode() | MODE_IMM;
+                        op[2] = *value;
+                    }
+                }
+                3
+            }
+            Trap { label } => {
+                op[0] = OP_TRAP;
+                op[1] = *label;
+                2
+            }
         };
-        if buf.len() >= op.len() {
+        if buf.len() >= len {
             buf[..len].copy_from_slice(&op[..len]);
             Ok(len)
         } else {
@@ -233,63 +626,123 @@ impl Read for OpCode<u8> {
 }
 
 impl OpCode<u8> {
+    /// Decodes the instruction at the start of `memory`, returning it along with its length in
+    /// bytes
     pub fn read_from(memory: &[u8]) -> Result<(Self, u8), ReadOpCodeError> {
-        // Get a memory address
-        if memory.len() == 0 {
-            return Err(ReadOpCodeError::OutOfBounds);
-        }
-        match memory[0] {
-            OP_ADD => Ok((OpCode::Add, 1)),
-            OP_SUB => Ok((OpCode::Sub, 1)),
-            OP_MUL => Ok((OpCode::Mul, 1)),
-            OP_DIV => Ok((OpCode::Div, 1)),
-            OP_JMP => Ok((OpCode::Jmp { label: 0 }, 2)),
-            OP_JMPC => Ok((OpCode::JmpCondition { label: 0 }, 1)),
+        use OpCode::*;
+        // Reads the byte at the given offset into the instruction
+        let byte = |offset: usize| {
+            memory
+                .get(offset)
+                .copied()
+                .ok_or(ReadOpCodeError::OutOfBounds)
+        };
+        // Reads the register at the given offset into the instruction
+        let register = |offset: usize| {
+            byte(offset).and_then(|index| {
+                Register::try_from(index).map_err(|_| ReadOpCodeError::InvalidRegister)
+            })
+        };
+        let opcode = byte(0)?;
+        if opcode & OPERAND_MODES != 0 {
+            let op = ArithOp::ALL
+                .iter()
+                .copied()
+                .find(|op| op.opcode() == opcode & !OPERAND_MODES)
+                .ok_or(ReadOpCodeError::InvalidOpcode)?;
+            let lhs = register(1)?;
+            let rhs = match opcode & OPERAND_MODES {
+                MODE_REG => Operand::Register(register(2)?),
+                MODE_IMM => Operand::Immediate(byte(2)?),
+                _ => return Err(ReadOpCodeError::InvalidOpcode),
+            };
+            return Ok((Arithmetic { op, lhs, rhs }, 3));
+        }
+        match opcode {
+            OP_ADD => Ok((Add, 1)),
+            OP_SUB => Ok((Sub, 1)),
+            OP_MUL => Ok((Mul, 1)),
+            OP_DIV => Ok((Div, 1)),
+            OP_JMP => Ok((Jmp { label: byte(1)? }, 2)),
+            OP_JMPC => Ok((JmpCondition { label: byte(1)? }, 2)),
             OP_MOVR => Ok((
-                OpCode::MovReg {
-                    to: Register::RGA,
-                    from: Register::RGB,
+                MovReg {
+                    to: register(1)?,
+                    from: register(2)?,
                 },
-                1,
+                3,
             )),
             OP_MOVIMM => Ok((
-                OpCode::MovImm {
-                    to: Register::RGA,
-                    from: 0,
+                MovImm {
+                    to: register(1)?,
+                    from: byte(2)?,
                 },
-                1,
+                3,
             )),
             OP_MOVADDR => Ok((
-                OpCode::MovAddr {
-                    to: Register::RGA,
-                    from: 0,
+                MovAddr {
+                    to: register(1)?,
+                    from: byte(2)?,
                 },
-                1,
+                3,
             )),
-            OP_STACKGET => Ok((OpCode::StackGet, 1)),
-            OP_STACKSET => Ok((OpCode::StackSet, 1)),
-            OP_EXEC => Ok((OpCode::Exec, 1)),
-            OP_RETURN => Ok((OpCode::Return, 1)),
-            OP_CMPCALLADDR => Ok((OpCode::CmpCallAddr { component: 0 }, 1)),
-            OP_CMPCALLIMM => Ok((OpCode::CmpCallImm { component: 0 }, 1)),
-            OP_FORWARD => Ok((OpCode::Forward, 1)),
-            OP_ROTATE => Ok((OpCode::Rotate, 1)),
-            OP_BREAK => Ok((OpCode::Break, 1)),
-            OP_BATTERY => Ok((OpCode::Battery, 1)),
-            OP_INVENTORYGET => Ok((OpCode::InventoryGet, 1)),
-            OP_INVENTORYDROP => Ok((OpCode::InventoryDrop, 1)),
-            OP_INVENTORYITEM => Ok((OpCode::InventoryItem, 1)),
-            OP_OUTPUT => Ok((OpCode::Output, 1)),
-            OP_NOOP => Ok((OpCode::Noop, 1)),
-            _ => {
-                return Err(ReadOpCodeError::InvalidOpcode);
-            }
+            OP_STACKGET => Ok((StackGet { to: register(1)? }, 2)),
+            OP_STACKSET => Ok((StackSet { from: register(1)? }, 2)),
+            OP_EXEC => Ok((Exec, 1)),
+            OP_RETURN => Ok((Return, 1)),
+            OP_CMPCALLADDR => Ok((
+                CmpCallAddr {
+                    component: byte(1)?,
+                },
+                2,
+            )),
+            OP_CMPCALLIMM => Ok((
+                CmpCallImm {
+                    component: byte(1)?,
+                },
+                2,
+            )),
+            OP_FORWARD => Ok((Forward, 1)),
+            OP_ROTATE => Ok((Rotate, 1)),
+            OP_BREAK => Ok((Break, 1)),
+            OP_BATTERY => Ok((Battery, 1)),
+            OP_INVENTORYGET => Ok((InventoryGet, 1)),
+            OP_INVENTORYDROP => Ok((InventoryDrop, 1)),
+            OP_INVENTORYITEM => Ok((InventoryItem, 1)),
+            OP_OUTPUT => Ok((Output, 1)),
+            OP_NOOP => Ok((Noop, 1)),
+            OP_CALL => Ok((Call { label: byte(1)? }, 2)),
+            OP_CMP => Ok((Cmp, 1)),
+            opcode @ OP_JZ..=OP_JNN => Ok((
+                JmpIf {
+                    condition: Condition::ALL[usize::from(opcode - OP_JZ)],
+                    label: byte(1)?,
+                },
+                2,
+            )),
+            OP_LOAD => Ok((Load { to: register(1)? }, 2)),
+            OP_STORE => Ok((Store { from: register(1)? }, 2)),
+            OP_AND => Ok((And, 1)),
+            OP_OR => Ok((Or, 1)),
+            OP_XOR => Ok((Xor, 1)),
+            OP_NOT => Ok((Not, 1)),
+            OP_SHL => Ok((Shl, 1)),
+            OP_SHR => Ok((Shr, 1)),
+            OP_MOD => Ok((Mod, 1)),
+            OP_TRAP => Ok((Trap { label: byte(1)? }, 2)),
+            _ => Err(ReadOpCodeError::InvalidOpcode),
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ReadOpCodeError {
+    /// The instruction runs past the end of memory
     OutOfBounds,
+    /// The first byte isn't a known opcode
     InvalidOpcode,
+    /// A register operand doesn't name a register
+    InvalidRegister,
 }
 
 #[derive(Debug)]
@@ -305,7 +758,7 @@ impl FromStr for AssemblyLine {
     type Err = AssemblyLineParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut tokens = s.trim().split_whitespace();
+        let mut tokens = s.split_whitespace();
         use AssemblyLine::*;
         use AssemblyLineParseError::*;
         use OpCode::*;
@@ -323,20 +776,17 @@ impl FromStr for AssemblyLine {
             "let" => {
                 let label = tokens
                     .next()
-                    .ok_or_else(|| LetMissingLabel)?
+                    .ok_or(LetMissingLabel)?
                     .to_lowercase()
                     .parse()
                     .map_err(LetInvalidLabel)?;
-                tokens
-                    .next()
-                    .ok_or_else(|| LetMissingEquals)
-                    .and_then(|symbol| {
-                        if symbol == "=" {
-                            Ok(())
-                        } else {
-                            Err(LetMissingEquals)
-                        }
-                    })?;
+                tokens.next().ok_or(LetMissingEquals).and_then(|symbol| {
+                    if symbol == "=" {
+                        Ok(())
+                    } else {
+                        Err(LetMissingEquals)
+                    }
+                })?;
                 let value = tokens
                     .next()
                     .unwrap_or("0")
@@ -344,24 +794,15 @@ impl FromStr for AssemblyLine {
                     .map_err(|_| LetInvalidValue)?;
                 Ok(MemoryDeclaration { label, value })
             }
-            "add" => Ok(Op(Add)),
-            "sub" => Ok(Op(Sub)),
-            "mul" => Ok(Op(Mul)),
-            "div" => Ok(Op(Div)),
+            "not" => Ok(Op(Not)),
             "jmp" => {
-                let label = tokens
-                    .next()
-                    .ok_or_else(|| JmpMissingArgument)?
-                    .to_lowercase();
+                let label = tokens.next().ok_or(JmpMissingArgument)?.to_lowercase();
                 LabelString::from_str(&label)
                     .map(|label| Op(Jmp { label }))
                     .map_err(JmpInvalidArgument)
             }
             "jmpc" => {
-                let label = tokens
-                    .next()
-                    .ok_or_else(|| JmpcMissingArgument)?
-                    .to_lowercase();
+                let label = tokens.next().ok_or(JmpcMissingArgument)?.to_lowercase();
                 LabelString::from_str(&label)
                     .map(|label| Op(JmpCondition { label }))
                     .map_err(JmpcInvalidArgument)
@@ -369,10 +810,10 @@ impl FromStr for AssemblyLine {
             "mov" => {
                 let to: Register = tokens
                     .next()
-                    .ok_or_else(|| MovMissingTo)?
+                    .ok_or(MovMissingTo)?
                     .parse()
                     .map_err(MovInvalidToRegister)?;
-                let from_token = tokens.next().ok_or_else(|| MovMissingFrom)?;
+                let from_token = tokens.next().ok_or(MovMissingFrom)?;
                 match Register::from_str(from_token) {
                     Ok(register) => Ok(Op(MovReg { to, from: register })),
                     Err(_) => match u8::from_str(from_token) {
@@ -388,12 +829,23 @@ impl FromStr for AssemblyLine {
                     },
                 }
             }
-            "stac_g" => Ok(Op(StackGet)),
-            "stac_s" => Ok(Op(StackSet)),
+            "stac_g" | "stac_s" | "load" | "store" => {
+                let register = tokens
+                    .next()
+                    .ok_or(MissingRegister)?
+                    .parse()
+                    .map_err(InvalidRegister)?;
+                Ok(Op(match first_token.as_str() {
+                    "stac_g" => StackGet { to: register },
+                    "stac_s" => StackSet { from: register },
+                    "load" => Load { to: register },
+                    _ => Store { from: register },
+                }))
+            }
             "exec" => Ok(Op(Exec)),
             "return" => Ok(Op(Return)),
             "cmp_call" => {
-                let component = tokens.next().ok_or_else(|| CmpCallMissingComponent)?;
+                let component = tokens.next().ok_or(CmpCallMissingComponent)?;
                 match u8::from_str(component) {
                     Ok(imm) => Ok(Op(CmpCallImm { component: imm })),
                     Err(_) => LabelString::from_str(component)
@@ -402,7 +854,7 @@ impl FromStr for AssemblyLine {
                                 component: component_label,
                             })
                         })
-                        .map_err(MovInvalidFrom),
+                        .map_err(CmpCallInvalidComponent),
                 }
             }
             "fwd" => Ok(Op(Forward)),
@@ -414,8 +866,41 @@ impl FromStr for AssemblyLine {
             "item" => Ok(Op(InventoryItem)),
             "out" => Ok(Op(Output)),
             "noop" => Ok(Op(Noop)),
+            "call" => {
+                let label = tokens.next().ok_or(CallMissingArgument)?.to_lowercase();
+                LabelString::from_str(&label)
+                    .map(|label| Op(Call { label }))
+                    .map_err(CallInvalidArgument)
+            }
+            "trap" => {
+                let label = tokens.next().ok_or(TrapMissingArgument)?.to_lowercase();
+                LabelString::from_str(&label)
+                    .map(|label| Op(Trap { label }))
+                    .map_err(TrapInvalidArgument)
+            }
             line => {
-                if line.ends_with(':') {
+                if let Some(&op) = ArithOp::ALL.iter().find(|op| op.mnemonic() == line) {
+                    let lhs = match tokens.next() {
+                        Some(token) => token.parse().map_err(InvalidRegister)?,
+                        None => return Ok(Op(op.shorthand())),
+                    };
+                    let rhs_token = tokens.next().ok_or(MissingOperand)?;
+                    let rhs = match Register::from_str(rhs_token) {
+                        Ok(register) => Operand::Register(register),
+                        Err(_) => {
+                            Operand::Immediate(u8::from_str(rhs_token).map_err(|_| InvalidOperand)?)
+                        }
+                    };
+                    Ok(Op(Arithmetic { op, lhs, rhs }))
+                } else if let Some(&condition) = Condition::ALL
+                    .iter()
+                    .find(|condition| condition.mnemonic() == line)
+                {
+                    let label = tokens.next().ok_or(JmpIfMissingArgument)?.to_lowercase();
+                    LabelString::from_str(&label)
+                        .map(|label| Op(JmpIf { condition, label }))
+                        .map_err(JmpIfInvalidArgument)
+                } else if line.ends_with(':') {
                     let line = line.trim_end_matches(':');
                     // Add a dollar sign to parse it like a label
                     let line = format!("${}", line);
@@ -439,17 +924,270 @@ pub enum AssemblyLineParseError {
     JmpInvalidArgument(LabelParseError),
     JmpcMissingArgument,
     JmpcInvalidArgument(LabelParseError),
+    CallMissingArgument,
+    CallInvalidArgument(LabelParseError),
+    TrapMissingArgument,
+    TrapInvalidArgument(LabelParseError),
+    JmpIfMissingArgument,
+    JmpIfInvalidArgument(LabelParseError),
     MovMissingTo,
     MovInvalidToRegister(<Register as FromStr>::Err),
     MovMissingFrom,
     MovInvalidFrom(LabelParseError),
     CmpCallMissingComponent,
+    CmpCallInvalidComponent(LabelParseError),
+    MissingRegister,
+    InvalidRegister(<Register as FromStr>::Err),
+    MissingOperand,
+    InvalidOperand,
     InvalidLabel(LabelParseError),
     InvalidInstruction,
+    ConstMissingName,
+    ConstInvalidName,
+    ConstAlreadyDefined,
+    ConstMissingEquals,
+    ConstInvalidValue,
+    MacroMissingName,
+    MacroInvalidName,
+    MacroAlreadyDefined,
+    /// The index of the token with the bad parameter name
+    MacroInvalidParameter(usize),
+    MacroArgumentCount {
+        expected: usize,
+        found: usize,
+    },
+    MacroExpansionTooLarge,
+    MacroWithoutEndm,
+    EndmWithoutMacro,
+    DirectiveInMacro,
+    IncludeMissingName,
+    IncludeInvalidName,
+    IncludeNotFound,
+}
+
+impl AssemblyLineParseError {
+    /// Index of the whitespace-separated token the error is about
+    ///
+    /// For missing tokens this is where the token should have been, which may be past the end of
+    /// the line.
+    fn token_index(&self) -> usize {
+        use AssemblyLineParseError::*;
+        match self {
+            InvalidLabel(_) | InvalidInstruction => 0,
+            LetMissingLabel | LetInvalidLabel(_) => 1,
+            LetMissingEquals => 2,
+            LetInvalidValue => 3,
+            JmpMissingArgument | JmpInvalidArgument(_) => 1,
+            JmpcMissingArgument | JmpcInvalidArgument(_) => 1,
+            CallMissingArgument | CallInvalidArgument(_) => 1,
+            TrapMissingArgument | TrapInvalidArgument(_) => 1,
+            JmpIfMissingArgument | JmpIfInvalidArgument(_) => 1,
+            MovMissingTo | MovInvalidToRegister(_) => 1,
+            MovMissingFrom | MovInvalidFrom(_) => 2,
+            CmpCallMissingComponent | CmpCallInvalidComponent(_) => 1,
+            MissingRegister | InvalidRegister(_) => 1,
+            MissingOperand | InvalidOperand => 2,
+            ConstMissingName | ConstInvalidName | ConstAlreadyDefined => 1,
+            ConstMissingEquals => 2,
+            ConstInvalidValue => 3,
+            MacroMissingName | MacroInvalidName | MacroAlreadyDefined => 1,
+            MacroInvalidParameter(index) => *index,
+            // Point at the first missing or extra argument
+            MacroArgumentCount { expected, found } => 1 + expected.min(found),
+            MacroExpansionTooLarge | MacroWithoutEndm | EndmWithoutMacro | DirectiveInMacro => 0,
+            IncludeMissingName | IncludeInvalidName | IncludeNotFound => 1,
+        }
+    }
+
+    /// Suggests a fix, given the token the error is about
+    fn suggestion(&self, token: Option<&str>) -> Option<String> {
+        use AssemblyLineParseError::*;
+        use LabelParseError::*;
+        let token = token.map(str::to_lowercase);
+        match (self, token) {
+            (InvalidInstruction, Some(token)) => closest(&token, MNEMONICS),
+            (MovInvalidToRegister(_), Some(token)) | (InvalidRegister(_), Some(token)) => {
+                closest(&token, REGISTER_NAMES)
+                    .or_else(|| closest(&format!("%{}", token), REGISTER_NAMES))
+            }
+            (InvalidOperand, Some(token)) => closest(&token, REGISTER_NAMES)
+                .or_else(|| closest(&format!("%{}", token), REGISTER_NAMES)),
+            (MovInvalidFrom(InvalidPrefix), Some(token)) => {
+                closest(&format!("%{}", token), REGISTER_NAMES)
+                    .or_else(|| Some(format!("${}", token)))
+            }
+            (LetInvalidLabel(InvalidPrefix), Some(token))
+            | (JmpInvalidArgument(InvalidPrefix), Some(token))
+            | (JmpcInvalidArgument(InvalidPrefix), Some(token))
+            | (CallInvalidArgument(InvalidPrefix), Some(token))
+            | (TrapInvalidArgument(InvalidPrefix), Some(token))
+            | (JmpIfInvalidArgument(InvalidPrefix), Some(token))
+            | (CmpCallInvalidComponent(InvalidPrefix), Some(token)) => Some(format!("${}", token)),
+            _ => None,
+        }
+        .map(|fix| format!("did you mean {}?", fix))
+        .or_else(|| match self {
+            LetMissingEquals | LetMissingLabel => Some("write it as let $name = value".into()),
+            LetInvalidValue => Some("use a number from 0 to 255".into()),
+            ConstMissingName | ConstMissingEquals => Some("write it as .const NAME = value".into()),
+            ConstInvalidValue => Some("use a number from 0 to 255 or an earlier constant".into()),
+            MacroMissingName => Some("write it as .macro name params...".into()),
+            MacroWithoutEndm => Some("end the macro with .endm".into()),
+            IncludeMissingName | IncludeInvalidName => Some("write it as .include \"name\"".into()),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for AssemblyLineParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use AssemblyLineParseError::*;
+        match self {
+            LetMissingLabel => f.write_str("let needs a label to declare"),
+            LetInvalidLabel(err) => write!(f, "invalid memory label: {}", err),
+            LetMissingEquals => f.write_str("let needs an = between the label and the value"),
+            LetInvalidValue => f.write_str("let needs a number as its value"),
+            JmpMissingArgument => f.write_str("jmp needs a label to jump to"),
+            JmpInvalidArgument(err) => write!(f, "invalid jump label: {}", err),
+            JmpcMissingArgument => f.write_str("jmpc needs a label to jump to"),
+            JmpcInvalidArgument(err) => write!(f, "invalid jump label: {}", err),
+            CallMissingArgument => f.write_str("call needs a label to call"),
+            CallInvalidArgument(err) => write!(f, "invalid call label: {}", err),
+            TrapMissingArgument => f.write_str("trap needs a label to handle faults at"),
+            TrapInvalidArgument(err) => write!(f, "invalid fault handler label: {}", err),
+            JmpIfMissingArgument => f.write_str("conditional jumps need a label to jump to"),
+            JmpIfInvalidArgument(err) => write!(f, "invalid jump label: {}", err),
+            MovMissingTo => f.write_str("mov needs a register to move into"),
+            MovInvalidToRegister(_) => f.write_str("mov can only move into a register"),
+            MovMissingFrom => f.write_str("mov needs a register, number or label to move from"),
+            MovInvalidFrom(err) => write!(
+                f,
+                "mov can only move from a register, number or label: {}",
+                err
+            ),
+            CmpCallMissingComponent => f.write_str("cmp_call needs a component to call"),
+            CmpCallInvalidComponent(err) => write!(
+                f,
+                "cmp_call needs a number or label as its component: {}",
+                err
+            ),
+            MissingRegister => f.write_str("this instruction needs a register"),
+            InvalidRegister(_) => f.write_str("unknown register"),
+            MissingOperand => {
+                f.write_str("this instruction needs a register or number after the first register")
+            }
+            InvalidOperand => {
+                f.write_str("the second operand needs to be a register or a number from 0 to 255")
+            }
+            InvalidLabel(err) => write!(f, "invalid label: {}", err),
+            InvalidInstruction => f.write_str("unknown instruction"),
+            ConstMissingName => f.write_str(".const needs a name to define"),
+            ConstInvalidName => {
+                f.write_str("constant names need to be letters, digits and underscores")
+            }
+            ConstAlreadyDefined => f.write_str("constant is already defined"),
+            ConstMissingEquals => f.write_str(".const needs an = between the name and the value"),
+            ConstInvalidValue => f.write_str(".const needs a number as its value"),
+            MacroMissingName => f.write_str(".macro needs a name to define"),
+            MacroInvalidName => f.write_str(
+                "macro names need to be letters, digits and underscores, and not an instruction",
+            ),
+            MacroAlreadyDefined => f.write_str("macro is already defined"),
+            MacroInvalidParameter(_) => {
+                f.write_str("macro parameters need to be unique letters, digits and underscores")
+            }
+            MacroArgumentCount { expected, found } => write!(
+                f,
+                "macro needs {} argument(s) but was given {}",
+                expected, found
+            ),
+            MacroExpansionTooLarge => write!(
+                f,
+                "macros expand to more than {} lines in total",
+                MAX_MACRO_LINES
+            ),
+            MacroWithoutEndm => f.write_str(".macro is never closed by .endm"),
+            EndmWithoutMacro => f.write_str(".endm without a .macro to close"),
+            DirectiveInMacro => f.write_str("macros can't contain directives"),
+            IncludeMissingName => f.write_str(".include needs the name of a snippet"),
+            IncludeInvalidName => f.write_str("snippet names need to be in quotes"),
+            IncludeNotFound => f.write_str("no snippet in the library has that name"),
+        }
+    }
+}
+
+/// Every instruction and keyword that can start a line
+const MNEMONICS: &[&str] = &[
+    "let", "add", "sub", "mul", "div", "mod", "and", "or", "xor", "not", "shl", "shr", "cmp",
+    "jmp", "jmpc", "jz", "jnz", "jc", "jnc", "jn", "jnn", "call", "mov", "load", "store", "stac_g",
+    "stac_s", "exec", "return", "cmp_call", "fwd", "rot", "brk", "bttry", "inven", "drop", "item",
+    "out", "noop", "trap", ".const", ".macro", ".endm", ".include",
+];
+
+/// Whether `mnemonic` is an instruction, as given by `OpCode::mnemonic`, rather than a keyword
+pub fn is_instruction(mnemonic: &str) -> bool {
+    mnemonic != "let" && !mnemonic.starts_with('.') && MNEMONICS.contains(&mnemonic)
 }
 
+/// Every register name
+const REGISTER_NAMES: &[&str] = &["%rga", "%rgb", "%rgc", "%rgd", "%ret", "%mem", "%flg"];
+
+/// Finds the candidate that is closest to `token`, if any is close enough to be a likely typo
+fn closest(token: &str, candidates: &[&str]) -> Option<String> {
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(token, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= 2 && *distance < candidate.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// Number of single character insertions, deletions and substitutions to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = prev[j] + if a_char == *b_char { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Splits a line into whitespace-separated tokens along with their start and end columns
+fn tokens_with_columns(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let chars = line.char_indices().enumerate();
+    for (column, (byte, c)) in chars {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some((column, byte)),
+            (Some((start_column, start_byte)), true) => {
+                tokens.push((start_column, column, &line[start_byte..byte]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((start_column, start_byte)) = start {
+        tokens.push((start_column, line.chars().count(), &line[start_byte..]));
+    }
+    tokens
+}
+
+#[derive(Clone)]
 pub struct LabelString(String);
 
+impl LabelString {
+    /// The label without its `$` or `@` prefix
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
 impl FromStr for LabelString {
     type Err = LabelParseError;
 
@@ -470,7 +1208,7 @@ impl FromStr for LabelString {
                 if Register::from_str(&maybe_register_label).is_ok() {
                     Err(RegisterName)
                 } else {
-                    Ok(Self(label.into()))
+                    Ok(Self(label))
                 }
             }
         } else {
@@ -486,26 +1224,60 @@ pub enum LabelParseError {
     RegisterName,
 }
 
-#[derive(Clone, Copy)]
+impl fmt::Display for LabelParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use LabelParseError::*;
+        f.write_str(match self {
+            InvalidPrefix => "labels start with $",
+            NumericName => "labels can't be numbers",
+            RegisterName => "labels can't be register names",
+        })
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Register {
     RGA,
     RGB,
     RGC,
     RGD,
     RET,
+    /// Address in data memory that `load` and `store` use
     MEM,
+    /// Flags set by arithmetic and `cmp`
+    FLG,
 }
 
-impl Into<u8> for &Register {
-    fn into(self) -> u8 {
+impl From<&Register> for u8 {
+    fn from(register: &Register) -> u8 {
         use Register::*;
-        match self {
+        match register {
             RGA => 0,
             RGB => 1,
             RGC => 2,
             RGD => 3,
             RET => 4,
             MEM => 5,
+            FLG => 6,
+        }
+    }
+}
+
+impl TryFrom<u8> for Register {
+    type Error = RegisterParseError;
+
+    fn try_from(index: u8) -> Result<Self, Self::Error> {
+        use Register::*;
+        match index {
+            0 => Ok(RGA),
+            1 => Ok(RGB),
+            2 => Ok(RGC),
+            3 => Ok(RGD),
+            4 => Ok(RET),
+            5 => Ok(MEM),
+            6 => Ok(FLG),
+            _ => Err(RegisterParseError::InvalidRegister),
         }
     }
 }
@@ -528,176 +1300,1098 @@ impl FromStr for Register {
             "%rgd" => Ok(RGD),
             "%ret" => Ok(RET),
             "%mem" => Ok(MEM),
+            "%flg" => Ok(FLG),
             _ => Err(InvalidRegister),
         }
     }
 }
 
-pub fn parse_code(code: String) -> Result<Vec<AssemblyLine>, Vec<CodeError>> {
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Register::*;
+        f.write_str(match self {
+            RGA => "%rga",
+            RGB => "%rgb",
+            RGC => "%rgc",
+            RGD => "%rgd",
+            RET => "%ret",
+            MEM => "%mem",
+            FLG => "%flg",
+        })
+    }
+}
+
+impl CodeError {
+    /// Builds a diagnostic pointing at the part of `text` the error is about
+    fn new(file: Option<&str>, line: usize, text: &str, error: AssemblyLineParseError) -> Self {
+        let tokens = tokens_with_columns(text);
+        // Point at the bad token, or at the last one if the bad token is missing
+        let token = tokens
+            .get(error.token_index())
+            .or_else(|| tokens.last())
+            .copied();
+        let (start, end) = token.map_or((0, 0), |(start, end, _)| (start, end));
+        let found = tokens.get(error.token_index()).map(|(_, _, token)| *token);
+        Self {
+            file: file.map(String::from),
+            line,
+            start,
+            end,
+            severity: Severity::Error,
+            message: error.to_string(),
+            suggestion: error.suggestion(found),
+            error,
+        }
+    }
+
+    /// Builds a diagnostic for an error in a line that came from a macro, pointing at where the
+    /// macro was used
+    fn in_macro(
+        file: Option<&str>,
+        line: usize,
+        text: &str,
+        name: &str,
+        error: AssemblyLineParseError,
+    ) -> Self {
+        let (start, end) = tokens_with_columns(text)
+            .first()
+            .map_or((0, 0), |(start, end, _)| (*start, *end));
+        Self {
+            file: file.map(String::from),
+            line,
+            start,
+            end,
+            severity: Severity::Error,
+            message: format!("in macro {}: {}", name, error),
+            suggestion: error.suggestion(None),
+            error,
+        }
+    }
+}
+
+/// A `.macro` block, pasted in wherever its name is used like an instruction
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    /// Every line between `.macro` and `.endm`
+    body: Vec<String>,
+    /// How many macros were defined before this one, which are the only ones it can use
+    order: usize,
+}
+
+/// Everything defined by `.const`, `.macro` and `.include` so far
+struct Preprocessor<'a> {
+    library: &'a Library,
+    constants: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    /// Names of the snippets that have been included, which are only included once
+    included: HashSet<String>,
+    /// How many lines macros have expanded to so far
+    macro_lines: usize,
+}
+
+/// Most lines that macros can expand to in a single upload, which is far more than could fit in
+/// memory but stops nested macros from expanding exponentially
+const MAX_MACRO_LINES: usize = 4 * (BIOS_MEM_SIZE + DATA_MEM_SIZE);
+
+impl<'a> Preprocessor<'a> {
+    fn new(library: &'a Library) -> Self {
+        Self {
+            library,
+            constants: HashMap::new(),
+            macros: HashMap::new(),
+            included: HashSet::new(),
+            macro_lines: 0,
+        }
+    }
+
+    /// Handles an `.include "name"` line, giving the snippet's name and code unless it has already
+    /// been included
+    fn include(&mut self, line: &str) -> Result<Option<(String, &'a str)>, AssemblyLineParseError> {
+        use AssemblyLineParseError::*;
+        let name = line.split_whitespace().nth(1).ok_or(IncludeMissingName)?;
+        let name = name
+            .strip_prefix('"')
+            .and_then(|name| name.strip_suffix('"'))
+            .map(str::to_lowercase)
+            .filter(|name| is_identifier(name))
+            .ok_or(IncludeInvalidName)?;
+        let code = self.library.get(&name).ok_or(IncludeNotFound)?;
+        if self.included.insert(name.clone()) {
+            Ok(Some((name, code)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Handles a `.const NAME = value` line
+    fn define_constant(&mut self, line: &str) -> Result<(), AssemblyLineParseError> {
+        use AssemblyLineParseError::*;
+        let mut tokens = line.split_whitespace().skip(1);
+        let name = tokens.next().ok_or(ConstMissingName)?.to_lowercase();
+        if !is_identifier(&name) {
+            return Err(ConstInvalidName);
+        }
+        if self.constants.contains_key(&name) {
+            return Err(ConstAlreadyDefined);
+        }
+        if tokens.next() != Some("=") {
+            return Err(ConstMissingEquals);
+        }
+        // Constants can be defined in terms of earlier ones
+        let value = tokens.next().ok_or(ConstInvalidValue)?.to_lowercase();
+        let value = self
+            .constants
+            .get(&value)
+            .copied()
+            .or_else(|| value.parse().ok())
+            .ok_or(ConstInvalidValue)?;
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    /// Handles a `.macro name params...` line, giving the macro to fill in with its body
+    fn start_macro(&self, line: &str) -> Result<Macro, AssemblyLineParseError> {
+        use AssemblyLineParseError::*;
+        let mut tokens = line.split_whitespace().skip(1);
+        let name = tokens.next().ok_or(MacroMissingName)?.to_lowercase();
+        if !is_identifier(&name) || MNEMONICS.contains(&name.as_str()) {
+            return Err(MacroInvalidName);
+        }
+        if self.macros.contains_key(&name) {
+            return Err(MacroAlreadyDefined);
+        }
+        let mut params = Vec::new();
+        for (index, param) in tokens.enumerate() {
+            let param = param.to_lowercase();
+            if !is_identifier(&param) || params.contains(&param) {
+                return Err(MacroInvalidParameter(index + 2));
+            }
+            params.push(param);
+        }
+        Ok(Macro {
+            name,
+            params,
+            body: Vec::new(),
+            order: self.macros.len(),
+        })
+    }
+
+    /// Expands `line` into the lines it stands for, along with the macro each one came from
+    ///
+    /// Only macros defined before the `visible`th one are expanded, so a macro can never end up
+    /// using itself. Expanding stops with an error once macros have produced `limit` lines.
+    fn expand(
+        &self,
+        line: &str,
+        visible: usize,
+        from: Option<&str>,
+        limit: usize,
+        out: &mut Vec<(Option<String>, Result<String, AssemblyLineParseError>)>,
+    ) -> Result<(), AssemblyLineParseError> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let name = tokens.first().map(|name| name.to_lowercase());
+        let found = name
+            .and_then(|name| self.macros.get(&name))
+            .filter(|found| found.order < visible);
+        let found = match found {
+            Some(found) => found,
+            None => {
+                out.push((from.map(String::from), Ok(line.to_string())));
+                return Ok(());
+            }
+        };
+        let args = &tokens[1..];
+        if args.len() != found.params.len() {
+            let error = AssemblyLineParseError::MacroArgumentCount {
+                expected: found.params.len(),
+                found: args.len(),
+            };
+            out.push((from.map(String::from), Err(error)));
+            return Ok(());
+        }
+        for body_line in &found.body {
+            if out.len() >= limit {
+                return Err(AssemblyLineParseError::MacroExpansionTooLarge);
+            }
+            let body_line = substitute_tokens(body_line, 0, |token| {
+                found
+                    .params
+                    .iter()
+                    .position(|param| *param == token)
+                    .map(|index| args[index].to_string())
+            });
+            self.expand(&body_line, found.order, Some(&found.name), limit, out)?;
+        }
+        Ok(())
+    }
+
+    /// Parses a line after swapping every constant for its value
+    fn parse(&self, line: &str) -> Result<AssemblyLine, AssemblyLineParseError> {
+        // The first token is the instruction, so constants can't shadow it
+        let line = substitute_tokens(line, 1, |token| {
+            self.constants.get(token).map(u8::to_string)
+        });
+        AssemblyLine::from_str(&line)
+    }
+
+    /// Parses the uploaded code, or a snippet with its name and the line of the upload that
+    /// included it
+    fn parse_file(
+        &mut self,
+        code: &str,
+        snippet: Option<(&str, usize)>,
+        lines: &mut Vec<(Location, AssemblyLine)>,
+        errors: &mut Vec<CodeError>,
+    ) {
+        use AssemblyLineParseError::*;
+        let file = snippet.map(|(name, _)| name);
+        // The macro being defined, along with where its definition starts. It's None if the
+        // `.macro` line was invalid, in which case the body is skipped.
+        let mut definition: Option<(usize, &str, Option<Macro>)> = None;
+        // Iterate over the given lines
+        for (line_num, line) in code.lines().enumerate() {
+            // Where this line ends up in the upload
+            let at = snippet.map_or(line_num, |(_, at)| at);
+            let directive = line.split_whitespace().next().map(str::to_lowercase);
+            if let Some((_, _, current)) = &mut definition {
+                match directive.as_deref() {
+                    Some(".endm") => {
+                        if let Some(defined) = current.take() {
+                            self.macros.insert(defined.name.clone(), defined);
+                        }
+                        definition = None;
+                    }
+                    Some(directive) if directive.starts_with('.') => {
+                        errors.push(CodeError::new(file, line_num, line, DirectiveInMacro))
+                    }
+                    _ => {
+                        if let Some(defined) = current {
+                            defined.body.push(line.to_string());
+                        }
+                    }
+                }
+                continue;
+            }
+            let result = match directive.as_deref() {
+                Some(".const") => self.define_constant(line),
+                Some(".macro") => match self.start_macro(line) {
+                    Ok(started) => {
+                        definition = Some((line_num, line, Some(started)));
+                        Ok(())
+                    }
+                    Err(err) => {
+                        definition = Some((line_num, line, None));
+                        Err(err)
+                    }
+                },
+                Some(".endm") => Err(EndmWithoutMacro),
+                Some(".include") => self.include(line).map(|included| {
+                    if let Some((name, code)) = included {
+                        self.parse_file(code, Some((&name, at)), lines, errors);
+                    }
+                }),
+                _ => {
+                    let mut expanded = Vec::new();
+                    let limit = MAX_MACRO_LINES.saturating_sub(self.macro_lines);
+                    let result = self.expand(line, usize::MAX, None, limit, &mut expanded);
+                    // Nothing from a line that expanded too far is kept
+                    if result.is_ok() {
+                        self.macro_lines +=
+                            expanded.iter().filter(|(from, _)| from.is_some()).count();
+                        for (from, text) in expanded {
+                            match (text.and_then(|text| self.parse(&text)), from) {
+                                (Ok(parsed), _) => lines.push((
+                                    Location {
+                                        file: file.map(String::from),
+                                        line: line_num,
+                                        upload_line: at,
+                                    },
+                                    parsed,
+                                )),
+                                (Err(err), None) => {
+                                    errors.push(CodeError::new(file, line_num, line, err))
+                                }
+                                (Err(err), Some(name)) => errors
+                                    .push(CodeError::in_macro(file, line_num, line, &name, err)),
+                            }
+                        }
+                    }
+                    result
+                }
+            };
+            if let Err(err) = result {
+                debug!("Error {:?} parsing line {:?}", err, line);
+                errors.push(CodeError::new(file, line_num, line, err))
+            }
+        }
+        if let Some((line_num, line, _)) = definition {
+            errors.push(CodeError::new(file, line_num, line, MacroWithoutEndm));
+        }
+    }
+}
+
+/// Whether `name` can be used for a constant, macro or macro parameter
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Rebuilds a line from its tokens, replacing each one from the `skip`th on that `replace` has a
+/// replacement for
+fn substitute_tokens(line: &str, skip: usize, replace: impl Fn(&str) -> Option<String>) -> String {
+    line.split_whitespace()
+        .enumerate()
+        .map(|(index, token)| {
+            if index < skip {
+                return token.to_string();
+            }
+            replace(&token.to_lowercase()).unwrap_or_else(|| token.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Where a parsed line came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Location {
+    /// The library snippet the line is in, or None if it's in the uploaded code
+    pub file: Option<String>,
+    /// Line counted from the start of its file
+    pub line: usize,
+    /// Line of the uploaded code, which for a snippet is where it was included
+    pub upload_line: usize,
+}
+
+/// Parses the code into the location and contents of every line, expanding constants, macros
+/// and includes
+///
+/// Lines from a macro all get the location of where it was used. Lines from an included snippet
+/// keep their line in the snippet, along with the line of the upload that included it.
+pub fn parse_code(
+    code: String,
+    library: &Library,
+) -> Result<Vec<(Location, AssemblyLine)>, Vec<CodeError>> {
     // Stores valid code lines
     let mut lines = Vec::new();
     // Stores errors
     let mut errors = Vec::new();
-    // Iterate over the given lines
-    for (line_num, line) in code.lines().enumerate() {
-        match AssemblyLine::from_str(&line) {
-            Ok(line) => lines.push(line),
-            Err(err) => {
-                println!("line: {}", line);
-                println!("error: {:?}", err);
-                errors.push(CodeError {
-                    line: line_num,
-                    error: err,
-                })
-            }
-        }
-    }
-    if errors.len() == 0 {
+    Preprocessor::new(library).parse_file(&code, None, &mut lines, &mut errors);
+    if errors.is_empty() {
         Ok(lines)
     } else {
         Err(errors)
     }
 }
 
-pub fn assemble(
-    code: &[AssemblyLine],
-    bios_memory: &mut [u8; BIOS_MEM_SIZE],
-    data_memory: &mut [u8; DATA_MEM_SIZE],
-) -> Result<(), AssemblingError> {
-    // Mapping of program label names to addresses
-    let mut label_locations = HashMap::new();
-    // Mapping of memory label names to addresses
-    let mut memory_locations = HashMap::new();
-    // Mapping of program label names to addresses where the should be an argument but the value is
-    // not known
-    let mut need_labels: HashMap<String, Vec<u8>> = HashMap::new();
-    // Mapping of memory label names to addresses where the should be an argument but the value is
-    // not known
-    let mut need_memory_labels: HashMap<String, Vec<u8>> = HashMap::new();
-    // Current offset in the program memory
-    let mut cur_offset: u8 = 0;
-    // Current offset in data memory
-    let mut cur_data_offset: u8 = 0;
+/// Where everything in a program ends up, worked out before emitting any code
+#[derive(Default)]
+struct SymbolTable {
+    /// Mapping of program label names to where they're defined and their address
+    labels: HashMap<String, (Location, u8)>,
+    /// Mapping of memory label names to where they're defined, their address and value
+    memory: HashMap<String, (Location, u8, u8)>,
+    /// Position in the code and address of every instruction that fits in memory
+    instructions: Vec<(usize, u8)>,
+    /// Every label reference as where it is, whether it's a program label, and the name
+    references: Vec<(Location, bool, String)>,
+}
 
-    use AssemblingError::*;
-    for (cur_line_num, line) in code.iter().enumerate() {
-        use AssemblyLine::*;
-        match line {
-            EmptyLine => {}
-            Label { name } => {
-                if let Some((defined_line, _)) = label_locations.get(&name.0) {
-                    return Err(DuplicateLabel {
-                        name: name.0.clone(),
-                        first_defined: *defined_line,
-                        redefined: cur_line_num,
-                    });
-                }
-                label_locations.insert(name.0.clone(), (cur_line_num, cur_offset));
-            }
-            MemoryDeclaration { label, value } => {
-                if let Some((defined_line, _, _)) = memory_locations.get(&label.0) {
-                    return Err(DuplicateMemory {
-                        name: label.0.clone(),
-                        first_defined: *defined_line,
-                        redefined: cur_line_num,
-                    });
+impl SymbolTable {
+    /// Walks the code once, recording every label, declaration and instruction address
+    fn build(
+        code: &[(Location, AssemblyLine)],
+        data_start: usize,
+        errors: &mut Vec<AssemblingError>,
+    ) -> Self {
+        use AssemblingError::*;
+        let mut symbols = Self::default();
+        // Current offset in the program memory, which can be just past the end if the code
+        // exactly fills it, or None once it has overflowed
+        let mut cur_offset = Some(0);
+        // Current offset in data memory, or None once it has run out
+        let mut cur_data_offset = u8::try_from(data_start).ok();
+
+        for (index, (cur_line, line)) in code.iter().enumerate() {
+            use AssemblyLine::*;
+            match line {
+                EmptyLine => {}
+                Label { name } => {
+                    if let Some((defined_line, _)) = symbols.labels.get(&name.0) {
+                        errors.push(DuplicateLabel {
+                            name: name.0.clone(),
+                            first_defined: defined_line.clone(),
+                            redefined: cur_line.clone(),
+                        });
+                    } else {
+                        // Labels after an overflow are reported on the instruction that overflowed
+                        let address = match cur_offset.map(u8::try_from) {
+                            Some(Ok(address)) => address,
+                            Some(Err(_)) => {
+                                errors.push(PointerOverflow {
+                                    line: cur_line.clone(),
+                                });
+                                cur_offset = None;
+                                u8::MAX
+                            }
+                            None => u8::MAX,
+                        };
+                        symbols
+                            .labels
+                            .insert(name.0.clone(), (cur_line.clone(), address));
+                    }
                 }
-                memory_locations.insert(label.0.clone(), (cur_line_num, cur_data_offset, value));
-                cur_data_offset += 1;
-            }
-            Op(opcode) => {
-                // Get a placeholder for the offset
-                let (mut placeholder, label_info) = opcode.placeholder_labels();
-                // Write the placeholder to memory
-                let bytes_written = placeholder
-                    .read(&mut bios_memory[usize::from(cur_offset)..])
-                    .map_err(MemoryOverflow)?;
-                // Add label placeholder info if needed
-                if let Some((is_program_label, arg_offset, label_name)) = label_info {
-                    let label_offset = cur_offset.checked_add(arg_offset).unwrap();
-                    if is_program_label {
-                        if let Some((_, label_address)) = label_locations.get(&label_name) {
-                            bios_memory[usize::from(label_offset)] = *label_address;
-                        } else {
-                            need_labels
-                                .entry(label_name)
-                                .and_modify(|v| v.push(label_offset))
-                                .or_insert_with(|| vec![label_offset]);
+                MemoryDeclaration { label, value } => {
+                    if let Some((defined_line, _, _)) = symbols.memory.get(&label.0) {
+                        errors.push(DuplicateMemory {
+                            name: label.0.clone(),
+                            first_defined: defined_line.clone(),
+                            redefined: cur_line.clone(),
+                        });
+                        continue;
+                    }
+                    match cur_data_offset {
+                        Some(offset) => {
+                            symbols
+                                .memory
+                                .insert(label.0.clone(), (cur_line.clone(), offset, *value));
+                            cur_data_offset = offset.checked_add(1);
                         }
-                    } else {
-                        if let Some((_, memory_address, _)) = memory_locations.get(&label_name) {
-                            bios_memory[usize::from(label_offset)] = *memory_address;
-                        } else {
-                            need_memory_labels
-                                .entry(label_name)
-                                .and_modify(|v| v.push(label_offset))
-                                .or_insert_with(|| vec![]);
+                        None => errors.push(DataOverflow {
+                            line: cur_line.clone(),
+                        }),
+                    }
+                }
+                Op(opcode) => {
+                    let (mut placeholder, label_info) = opcode.placeholder_labels();
+                    // Remember every reference, even if there's no room left for the instruction
+                    if let Some((is_program_label, _, label_name)) = label_info {
+                        symbols
+                            .references
+                            .push((cur_line.clone(), is_program_label, label_name));
+                    }
+                    let offset = match cur_offset.map(u8::try_from) {
+                        Some(Ok(offset)) => offset,
+                        // Memory is already full
+                        Some(Err(_)) => {
+                            errors.push(PointerOverflow {
+                                line: cur_line.clone(),
+                            });
+                            cur_offset = None;
+                            continue;
                         }
+                        // Memory already overflowed, which has been reported
+                        None => continue,
+                    };
+                    // Encode the placeholder just to find out how long it is
+                    let bytes_written = placeholder
+                        .read(&mut [0; MAX_OP_LEN])
+                        .expect("instructions fit in MAX_OP_LEN");
+                    if bytes_written > BIOS_MEM_SIZE - usize::from(offset) {
+                        errors.push(MemoryOverflow {
+                            line: cur_line.clone(),
+                        });
+                        cur_offset = None;
+                        continue;
                     }
+                    symbols.instructions.push((index, offset));
+                    cur_offset = Some(usize::from(offset) + bytes_written);
                 }
-                // Move the offset forward
-                let bytes_written: u8 = bytes_written.try_into().map_err(|_| PointerOverflow)?;
-                cur_offset = cur_offset
-                    .checked_add(bytes_written)
-                    .ok_or_else(|| PointerOverflow)?;
             }
         }
+        symbols
     }
-    // Iterate over all the locations that need program memory labels filled in
-    for (label_name, offsets) in &need_labels {
-        for offset in offsets {
-            if let Some((_, label_address)) = label_locations.get(label_name) {
-                bios_memory[usize::from(*offset)] = *label_address;
+}
+
+/// Assembles the code into BIOS or program memory, and data memory from `data_start` on
+///
+/// Everything that went wrong is returned, sorted by line. The memories hold as much of the code as
+/// could be assembled, but should only be used if there are no errors.
+pub fn assemble(
+    code: &[(Location, AssemblyLine)],
+    bios_memory: &mut [u8; BIOS_MEM_SIZE],
+    data_memory: &mut [u8; DATA_MEM_SIZE],
+    data_start: usize,
+) -> Vec<AssemblingError> {
+    use AssemblingError::*;
+    // Everything that went wrong
+    let mut errors = Vec::new();
+    // First pass: find out where every label, declaration and instruction goes
+    let symbols = SymbolTable::build(code, data_start, &mut errors);
+
+    // Second pass: emit every instruction that fits, with its label filled in
+    for &(index, offset) in &symbols.instructions {
+        let opcode = match &code[index].1 {
+            AssemblyLine::Op(opcode) => opcode,
+            _ => unreachable!("only instructions have addresses"),
+        };
+        let (mut placeholder, label_info) = opcode.placeholder_labels();
+        let mut encoded = [0; MAX_OP_LEN];
+        let len = placeholder
+            .read(&mut encoded)
+            .expect("instructions fit in MAX_OP_LEN");
+        // The first pass made sure there's room for it
+        let offset = usize::from(offset);
+        bios_memory[offset..offset + len].copy_from_slice(&encoded[..len]);
+        if let Some((is_program_label, arg_offset, label_name)) = label_info {
+            let address = if is_program_label {
+                symbols.labels.get(&label_name).map(|(_, address)| *address)
+            } else {
+                symbols
+                    .memory
+                    .get(&label_name)
+                    .map(|(_, address, _)| *address)
+            };
+            // Undefined labels are reported below
+            if let Some(address) = address {
+                let label_offset = offset + usize::from(arg_offset);
+                bios_memory[label_offset] = address;
                 debug!(
                     "Writing address {} for label {} to {}",
-                    label_name, label_address, offset
+                    address, label_name, label_offset
                 );
-            } else {
-                return Err(InvalidLabel);
             }
         }
     }
-    // Iterate over all the locations that need program memory labels filled in
-    for (label_name, offsets) in &need_memory_labels {
-        for offset in offsets {
-            if let Some((_, label_address, _)) = memory_locations.get(label_name) {
-                data_memory[usize::from(*offset)] = *label_address;
-                debug!(
-                    "Writing address {} for label {} to {}",
-                    label_name, label_address, offset
-                );
-            } else {
-                return Err(InvalidMemoryLabel);
-            }
+
+    // Report every reference to something that doesn't exist, grouped by name
+    let mut undefined: BTreeMap<(bool, &str), Vec<Location>> = BTreeMap::new();
+    for (line, is_program_label, name) in &symbols.references {
+        let defined = if *is_program_label {
+            symbols.labels.contains_key(name)
+        } else {
+            symbols.memory.contains_key(name)
+        };
+        if !defined {
+            undefined
+                .entry((*is_program_label, name))
+                .or_default()
+                .push(line.clone());
         }
     }
-    // Wipe data memory
-    for d in data_memory.iter_mut() {
+    for ((is_program_label, name), lines) in undefined {
+        let name = name.to_string();
+        errors.push(if is_program_label {
+            InvalidLabel { name, lines }
+        } else {
+            InvalidMemoryLabel { name, lines }
+        });
+    }
+
+    // Fill in our part of data memory with only the declared values
+    for d in data_memory.iter_mut().skip(data_start) {
         *d = 0;
     }
-    // Fill in data memory with defined values
-    for (_, (_, address, value)) in memory_locations {
-        data_memory[usize::from(address)] = *value;
+    for (_, address, value) in symbols.memory.values() {
+        data_memory[usize::from(*address)] = *value;
     }
-    dbg!(&need_labels);
-    dbg!(&need_memory_labels);
-    Ok(())
+    errors.sort_by_key(AssemblingError::line);
+    errors
 }
 
-#[derive(Debug)]
+/// How many bytes of BIOS or program memory the code assembles to
+pub fn code_len(code: &[(Location, AssemblyLine)]) -> usize {
+    let len: usize = code
+        .iter()
+        .filter_map(|(_, line)| match line {
+            AssemblyLine::Op(opcode) => Some(opcode),
+            _ => None,
+        })
+        .map(|opcode| {
+            opcode
+                .placeholder_labels()
+                .0
+                .read(&mut [0; MAX_OP_LEN])
+                .expect("instructions fit in MAX_OP_LEN")
+        })
+        .sum();
+    len.min(BIOS_MEM_SIZE)
+}
+
+/// How many bytes of data memory the code declares
+pub fn data_len(code: &[(Location, AssemblyLine)]) -> usize {
+    code.iter()
+        .filter(|(_, line)| matches!(line, AssemblyLine::MemoryDeclaration { .. }))
+        .count()
+}
+
+#[derive(Debug, Serialize)]
 pub enum AssemblingError {
     DuplicateLabel {
         name: String,
-        first_defined: usize,
-        redefined: usize,
+        first_defined: Location,
+        redefined: Location,
     },
     DuplicateMemory {
         name: String,
-        first_defined: usize,
-        redefined: usize,
+        first_defined: Location,
+        redefined: Location,
     },
-    MemoryOverflow(io::Error),
-    PointerOverflow,
-    InvalidLabel,
-    InvalidMemoryLabel,
+    /// The instruction on this line doesn't fit in memory
+    MemoryOverflow { line: Location },
+    /// The label or instruction on this line comes after the code has filled memory
+    PointerOverflow { line: Location },
+    /// The declaration on this line doesn't fit in data memory
+    DataOverflow { line: Location },
+    /// A label that was never defined, and every line that jumps to it
+    InvalidLabel { name: String, lines: Vec<Location> },
+    /// A memory label that was never declared, and every line that reads it
+    InvalidMemoryLabel { name: String, lines: Vec<Location> },
+}
+
+impl AssemblingError {
+    /// The first line of the uploaded code the error is about
+    pub fn line(&self) -> usize {
+        use AssemblingError::*;
+        match self {
+            DuplicateLabel { redefined, .. } | DuplicateMemory { redefined, .. } => {
+                redefined.upload_line
+            }
+            MemoryOverflow { line } | PointerOverflow { line } | DataOverflow { line } => {
+                line.upload_line
+            }
+            InvalidLabel { lines, .. } | InvalidMemoryLabel { lines, .. } => {
+                lines.first().map_or(0, |line| line.upload_line)
+            }
+        }
+    }
+}
+
+/// Assembles code that should have no errors, with its declarations from `data_start` on
+///
+/// Gives the BIOS or program memory, how much of it is code, and the data memory.
+#[cfg(test)]
+pub(crate) fn assemble_ok(
+    code: &str,
+    data_start: usize,
+) -> ([u8; BIOS_MEM_SIZE], usize, [u8; DATA_MEM_SIZE]) {
+    let code = parse_code(code.into(), &Library::default()).ok().unwrap();
+    let mut memory = [0; BIOS_MEM_SIZE];
+    let mut data = [0; DATA_MEM_SIZE];
+    let errors = assemble(&code, &mut memory, &mut data, data_start);
+    assert!(errors.is_empty(), "{:?}", errors);
+    (memory, code_len(&code), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn register() -> impl Strategy<Value = Register> {
+        (0u8..7).prop_map(|index| Register::try_from(index).unwrap())
+    }
+
+    fn opcode() -> impl Strategy<Value = OpCode<u8>> {
+        use OpCode::*;
+        prop_oneof![
+            Just(Add),
+            Just(Sub),
+            Just(Mul),
+            Just(Div),
+            any::<u8>().prop_map(|label| Jmp { label }),
+            any::<u8>().prop_map(|label| JmpCondition { label }),
+            (register(), register()).prop_map(|(to, from)| MovReg { to, from }),
+            (register(), any::<u8>()).prop_map(|(to, from)| MovImm { to, from }),
+            (register(), any::<u8>()).prop_map(|(to, from)| MovAddr { to, from }),
+            register().prop_map(|to| StackGet { to }),
+            register().prop_map(|from| StackSet { from }),
+            Just(Exec),
+            Just(Return),
+            any::<u8>().prop_map(|component| CmpCallAddr { component }),
+            any::<u8>().prop_map(|component| CmpCallImm { component }),
+            Just(Forward),
+            Just(Rotate),
+            Just(Break),
+            Just(Battery),
+            Just(InventoryGet),
+            Just(InventoryDrop),
+            Just(InventoryItem),
+            Just(Output),
+            Just(Noop),
+            any::<u8>().prop_map(|label| Call { label }),
+            Just(Cmp),
+            (prop::sample::select(&Condition::ALL[..]), any::<u8>())
+                .prop_map(|(condition, label)| JmpIf { condition, label }),
+            register().prop_map(|to| Load { to }),
+            register().prop_map(|from| Store { from }),
+            Just(And),
+            Just(Or),
+            Just(Xor),
+            Just(Not),
+            Just(Shl),
+            Just(Shr),
+            Just(Mod),
+            any::<u8>().prop_map(|label| Trap { label }),
+            (
+                prop::sample::select(&ArithOp::ALL[..]),
+                register(),
+                register()
+            )
+                .prop_map(|(op, lhs, rhs)| Arithmetic {
+                    op,
+                    lhs,
+                    rhs: Operand::Register(rhs)
+                }),
+            (
+                prop::sample::select(&ArithOp::ALL[..]),
+                register(),
+                any::<u8>()
+            )
+                .prop_map(|(op, lhs, rhs)| Arithmetic {
+                    op,
+                    lhs,
+                    rhs: Operand::Immediate(rhs)
+                }),
+        ]
+    }
+
+    /// Opcodes of arithmetic with explicit operands
+    fn operand_opcode() -> impl Strategy<Value = u8> {
+        (
+            prop::sample::select(&ArithOp::ALL[..]),
+            prop_oneof![Just(MODE_REG), Just(MODE_IMM)],
+        )
+            .prop_map(|(op, mode)| op.opcode() | mode)
+    }
+
+    fn encode(mut op: OpCode<u8>) -> Vec<u8> {
+        let mut buf = [0u8; 3];
+        let len = op.read(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn parse_error(line: &str) -> CodeError {
+        parse_code(line.into(), &Library::default())
+            .err()
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_bad_token() {
+        let error = parse_error("  mov %rgx 5");
+        assert_eq!((error.start, error.end), (6, 10));
+        assert_eq!(error.suggestion.as_deref(), Some("did you mean %rga?"));
+    }
+
+    #[test]
+    fn diagnostics_for_missing_tokens_point_at_the_last_token() {
+        let error = parse_error("jmp");
+        assert_eq!((error.start, error.end), (0, 3));
+        assert_eq!(error.message, "jmp needs a label to jump to");
+    }
+
+    #[test]
+    fn diagnostics_suggest_close_mnemonics_and_prefixes() {
+        assert_eq!(
+            parse_error("movv %rga 1").suggestion.as_deref(),
+            Some("did you mean mov?")
+        );
+        assert_eq!(
+            parse_error("jmp loop").suggestion.as_deref(),
+            Some("did you mean $loop?")
+        );
+        assert_eq!(
+            parse_error("mov %rga rgb").suggestion.as_deref(),
+            Some("did you mean %rgb?")
+        );
+        assert_eq!(parse_error("xyzzy").suggestion, None);
+    }
+
+    #[test]
+    fn assembling_reports_every_error() {
+        let code = "a:\na:\njmp $b\nlet $x = 1\nlet $x = 2\nlet $y = 3\njmp $b\n";
+        let code = parse_code(code.into(), &Library::default()).ok().unwrap();
+        let errors = assemble(&code, &mut [0; BIOS_MEM_SIZE], &mut [0; DATA_MEM_SIZE], 0);
+        let lines: Vec<_> = errors.iter().map(AssemblingError::line).collect();
+        assert_eq!(lines, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn code_can_exactly_fill_memory() {
+        let code = "top:\n".to_string() + &"noop\n".repeat(BIOS_MEM_SIZE - 2) + "jmp $top";
+        let (bios, _, _) = assemble_ok(&code, 0);
+        assert_eq!(&bios[BIOS_MEM_SIZE - 3..], &[OP_NOOP, OP_JMP, 0]);
+        assemble_ok(&"noop\n".repeat(BIOS_MEM_SIZE), 0);
+    }
+
+    #[test]
+    fn only_code_past_the_end_overflows() {
+        let errors_in = |code: String| {
+            let code = parse_code(code, &Library::default()).ok().unwrap();
+            let errors = assemble(&code, &mut [0; BIOS_MEM_SIZE], &mut [0; DATA_MEM_SIZE], 0);
+            errors
+                .iter()
+                .map(|err| match err {
+                    AssemblingError::PointerOverflow { line } => ("pointer", line.upload_line),
+                    AssemblingError::MemoryOverflow { line } => ("memory", line.upload_line),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let full = "noop\n".repeat(BIOS_MEM_SIZE);
+        assert_eq!(
+            errors_in(full.clone() + "noop\nnoop"),
+            vec![("pointer", BIOS_MEM_SIZE)]
+        );
+        assert_eq!(
+            errors_in(full.clone() + "end:\njmp $end"),
+            vec![("pointer", BIOS_MEM_SIZE)]
+        );
+        // The jump half fits
+        let almost = "noop\n".repeat(BIOS_MEM_SIZE - 1);
+        assert_eq!(
+            errors_in(almost + "a:\njmp $a"),
+            vec![("memory", BIOS_MEM_SIZE)]
+        );
+    }
+
+    #[test]
+    fn backward_program_labels_resolve() {
+        let (bios, _, _) = assemble_ok("noop\ntop:\njmp $top", 0);
+        assert_eq!(&bios[..3], &[OP_NOOP, OP_JMP, 1]);
+    }
+
+    #[test]
+    fn forward_program_labels_resolve() {
+        let (bios, _, _) = assemble_ok("jmpc $end\njmp $end\nnoop\nend:\nnoop", 0);
+        assert_eq!(&bios[..6], &[OP_JMPC, 5, OP_JMP, 5, OP_NOOP, OP_NOOP]);
+    }
+
+    #[test]
+    fn backward_memory_labels_resolve() {
+        let (bios, _, data) = assemble_ok("let $a = 7\nlet $b = 9\nmov %rga $b", 0);
+        assert_eq!(&bios[..3], &[OP_MOVADDR, 0, 1]);
+        assert_eq!(&data[..3], &[7, 9, 0]);
+    }
+
+    #[test]
+    fn forward_memory_labels_resolve_into_bios() {
+        let (bios, _, data) = assemble_ok(
+            "mov %rgb $b\nmov %rgc $b\ncmp_call $a\nlet $a = 7\nlet $b = 9",
+            0,
+        );
+        assert_eq!(
+            &bios[..8],
+            &[OP_MOVADDR, 1, 1, OP_MOVADDR, 2, 1, OP_CMPCALLADDR, 0]
+        );
+        // Only the declared values end up in data memory
+        assert_eq!(&data[..3], &[7, 9, 0]);
+    }
+
+    #[test]
+    fn arithmetic_operands_are_optional() {
+        let (bios, _, _) = assemble_ok("add\nadd %rgc %rgd\nshl %rga 3", 0);
+        assert_eq!(
+            &bios[..7],
+            &[OP_ADD, OP_ADD | MODE_REG, 2, 3, OP_SHL | MODE_IMM, 0, 3]
+        );
+        assert_eq!(
+            parse_error("add %rga").message,
+            "this instruction needs a register or number after the first register"
+        );
+        assert_eq!(
+            parse_error("div %rga rgb").suggestion.as_deref(),
+            Some("did you mean %rgb?")
+        );
+    }
+
+    #[test]
+    fn constants_become_immediates() {
+        let (bios, _, _) = assemble_ok(
+            ".const speed = 42\n.const fast = speed\nmov %rga FAST\ncmp_call speed",
+            0,
+        );
+        assert_eq!(&bios[..5], &[OP_MOVIMM, 0, 42, OP_CMPCALLIMM, 42]);
+    }
+
+    #[test]
+    fn macros_expand_where_they_are_used() {
+        let code = ".const n = 2\n.macro step times\nmov %rga times\nfwd\n.endm\n.macro go\nstep n\n.endm\nstep 3\ngo";
+        let lines: Vec<_> = parse_code(code.into(), &Library::default())
+            .ok()
+            .unwrap()
+            .into_iter()
+            .map(|(line, _)| line.upload_line)
+            .collect();
+        assert_eq!(lines, vec![8, 8, 9, 9]);
+        let (bios, _, _) = assemble_ok(code, 0);
+        let (expected, _, _) = assemble_ok("mov %rga 3\nfwd\nmov %rga 2\nfwd", 0);
+        assert_eq!(bios, expected);
+    }
+
+    #[test]
+    fn macro_errors_point_at_where_they_are_used() {
+        let error = parse_error(".macro m a\nmov %rgx a\n.endm\n  m 1");
+        assert_eq!((error.line, error.start, error.end), (3, 2, 3));
+        assert_eq!(
+            error.message,
+            "in macro m: mov can only move into a register"
+        );
+        let error = parse_error(".macro m a\n.endm\nm");
+        assert_eq!(error.message, "macro needs 1 argument(s) but was given 0");
+        assert_eq!(parse_error(".macro m\nnoop").line, 0);
+        assert_eq!(
+            parse_error(".const 1x = 2").message,
+            "constant names need to be letters, digits and underscores"
+        );
+    }
+
+    #[test]
+    fn macros_cannot_use_themselves() {
+        // `b` isn't defined yet where `a` is, so it's an unknown instruction there
+        let error = parse_error(".macro a\nb\n.endm\n.macro b\na\n.endm\nb");
+        assert_eq!(error.message, "in macro a: unknown instruction");
+    }
+
+    #[test]
+    fn nested_macros_cannot_expand_forever() {
+        // Every macro uses the one before it twice, doubling the expansion each time
+        let mut code = ".macro m0\nnoop\n.endm\n".to_string();
+        for level in 1..40 {
+            code += &format!(".macro m{}\nm{1}\nm{1}\n.endm\n", level, level - 1);
+        }
+        code += "noop\nm39\nm39";
+        let errors = parse_code(code, &Library::default()).err().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line, 160);
+        assert_eq!(
+            errors[0].message,
+            format!(
+                "macros expand to more than {} lines in total",
+                MAX_MACRO_LINES
+            )
+        );
+    }
+
+    fn library() -> Library {
+        let mut library = Library::default();
+        library.insert("turn", ".macro turn_around\nrot\nrot\n.endm".into());
+        library.insert("broken", "noop\nmov %rgx 1".into());
+        library.insert("first", "loop:\nnoop".into());
+        library.insert("second", "fwd\nloop:".into());
+        library
+    }
+
+    #[test]
+    fn includes_share_their_definitions() {
+        // Including the same snippet twice only includes it once
+        let code = ".include \"turn\"\n.include \"TURN\"\nturn_around";
+        let code = parse_code(code.into(), &library()).ok().unwrap();
+        let lines: Vec<_> = code.iter().map(|(line, _)| line.upload_line).collect();
+        assert_eq!(lines, vec![2, 2]);
+    }
+
+    #[test]
+    fn include_errors_point_into_the_snippet() {
+        let errors = parse_code("noop\n.include \"broken\"".into(), &library())
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].file.as_deref(), Some("broken"));
+        assert_eq!(errors[0].line, 1);
+        let errors = parse_code(".include \"missing\"".into(), &library())
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].file, None);
+        assert_eq!(errors[0].message, "no snippet in the library has that name");
+    }
+
+    #[test]
+    fn label_collisions_between_includes_are_duplicates() {
+        let code = ".include \"first\"\n.include \"second\"\njmp $loop";
+        let code = parse_code(code.into(), &library()).ok().unwrap();
+        let errors = assemble(&code, &mut [0; BIOS_MEM_SIZE], &mut [0; DATA_MEM_SIZE], 0);
+        match errors.as_slice() {
+            [AssemblingError::DuplicateLabel {
+                first_defined,
+                redefined,
+                ..
+            }] => {
+                // Both point at the line in the snippet, not the `.include`
+                let at = |file: &str, line, upload_line| Location {
+                    file: Some(file.into()),
+                    line,
+                    upload_line,
+                };
+                assert_eq!(first_defined, &at("first", 0, 0));
+                assert_eq!(redefined, &at("second", 1, 1));
+            }
+            errors => panic!("{:?}", errors),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decode_inverts_encode(op in opcode()) {
+            let bytes = encode(op.clone());
+            let (decoded, len) = OpCode::read_from(&bytes).unwrap();
+            prop_assert_eq!(decoded, op);
+            prop_assert_eq!(usize::from(len), bytes.len());
+        }
+
+        #[test]
+        fn encode_inverts_decode(
+            opcode in prop_oneof![OP_ADD..=OP_TRAP, operand_opcode()],
+            operands in any::<[u8; 2]>(),
+        ) {
+            let memory = [opcode, operands[0], operands[1]];
+            match OpCode::read_from(&memory) {
+                Ok((op, len)) => {
+                    prop_assert_eq!(encode(op), &memory[..usize::from(len)]);
+                }
+                Err(err) => {
+                    // Only register operands can be rejected
+                    prop_assert_eq!(err, ReadOpCodeError::InvalidRegister);
+                    prop_assert!(operands.iter().any(|&operand| operand > 6));
+                }
+            }
+        }
+
+        #[test]
+        fn truncated_instructions_are_out_of_bounds(op in opcode()) {
+            let bytes = encode(op);
+            for len in 0..bytes.len() {
+                prop_assert_eq!(
+                    OpCode::read_from(&bytes[..len]),
+                    Err(ReadOpCodeError::OutOfBounds)
+                );
+            }
+        }
+
+        #[test]
+        fn unknown_opcodes_are_invalid(opcode in (OP_TRAP + 1)..=u8::MAX) {
+            let is_arithmetic = ArithOp::ALL
+                .iter()
+                .any(|op| op.opcode() == opcode & !OPERAND_MODES);
+            prop_assume!(!is_arithmetic || opcode & OPERAND_MODES == OPERAND_MODES);
+            prop_assert_eq!(
+                OpCode::read_from(&[opcode, 0, 0]),
+                Err(ReadOpCodeError::InvalidOpcode)
+            );
+        }
+
+        #[test]
+        fn invalid_registers_are_rejected(
+            opcode in prop_oneof![
+                Just(OP_MOVR),
+                Just(OP_MOVIMM),
+                Just(OP_MOVADDR),
+                Just(OP_STACKGET),
+                Just(OP_STACKSET),
+                Just(OP_LOAD),
+                Just(OP_STORE),
+                operand_opcode(),
+            ],
+            index in 7u8..,
+        ) {
+            prop_assert_eq!(
+                OpCode::read_from(&[opcode, index, 0]),
+                Err(ReadOpCodeError::InvalidRegister)
+            );
+        }
+    }
 }
@@ -1,17 +1,160 @@
+use crate::asm::{self, OpCode};
 use cookie::Key;
 use hex::FromHexError;
 use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const KEY_MIN_LEN: usize = 64;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize)]
 pub struct Config {
     key: Option<String>,
+    #[serde(default)]
+    simulation: SimulationConfig,
+    #[serde(default)]
+    world: WorldConfig,
+    #[serde(default)]
+    persistence: PersistenceConfig,
+    #[serde(default)]
+    library: LibraryConfig,
+}
+
+/// Settings for the shared assembly snippets
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LibraryConfig {
+    /// Directory holding a `name.asm` file for every snippet
+    pub path: PathBuf,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("library"),
+        }
+    }
+}
+
+/// Settings for saving users and robots across restarts
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PersistenceConfig {
+    /// Where to save snapshots
+    pub path: PathBuf,
+    /// Seconds between snapshots
+    pub interval_secs: u64,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("snapshot.json"),
+            interval_secs: 60,
+        }
+    }
+}
+
+/// Settings for the shared map
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    /// Width of the map in tiles
+    pub width: usize,
+    /// Height of the map in tiles
+    pub height: usize,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            width: 64,
+            height: 64,
+        }
+    }
+}
+
+/// Settings for the world clock
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    /// Milliseconds between ticks
+    pub tick_ms: u64,
+    /// How many cycles each robot runs per tick
+    #[serde(alias = "instructions_per_tick")]
+    pub cycles_per_tick: u32,
+    /// Battery charge every robot gets back each tick
+    pub recharge_per_tick: u16,
+    /// What each instruction costs to run
+    pub costs: CostConfig,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: 100,
+            cycles_per_tick: 10,
+            recharge_per_tick: 5,
+            costs: CostConfig::default(),
+        }
+    }
+}
+
+/// How many cycles each instruction takes and how much battery charge it uses
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CostConfig {
+    /// Cost of any instruction that isn't listed
+    pub default: Cost,
+    /// Costs by mnemonic, such as `fwd` or `cmp_call`
+    pub instructions: BTreeMap<String, Cost>,
+}
+
+impl CostConfig {
+    /// Looks up the cost of running `op`
+    pub fn cost<L>(&self, op: &OpCode<L>) -> Cost {
+        self.instructions
+            .get(op.mnemonic())
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        let instructions = [
+            ("fwd", Cost::new(10, 25)),
+            ("rot", Cost::new(5, 10)),
+            ("brk", Cost::new(10, 25)),
+            ("cmp_call", Cost::new(5, 10)),
+        ]
+        .iter()
+        .map(|&(mnemonic, cost)| (mnemonic.to_string(), cost))
+        .collect();
+        Self {
+            default: Cost::new(1, 1),
+            instructions,
+        }
+    }
+}
+
+/// What running a single instruction costs
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct Cost {
+    /// Cycles the instruction takes, including the one it runs on
+    pub cycles: u32,
+    /// Battery charge the instruction uses
+    pub energy: u16,
+}
+
+impl Cost {
+    fn new(cycles: u32, energy: u16) -> Self {
+        Self { cycles, energy }
+    }
 }
 
 impl Config {
@@ -33,40 +176,82 @@ impl Config {
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self { key: None }
-    }
-}
-
 impl From<&SecureConfig> for Config {
     fn from(secure_config: &SecureConfig) -> Config {
         let key = hex::encode(&secure_config.key);
-        Config { key: Some(key) }
+        Config {
+            key: Some(key),
+            simulation: secure_config.simulation.clone(),
+            world: secure_config.world.clone(),
+            persistence: secure_config.persistence.clone(),
+            library: secure_config.library.clone(),
+        }
     }
 }
 
 pub struct SecureConfig {
     key: Vec<u8>,
+    simulation: SimulationConfig,
+    world: WorldConfig,
+    persistence: PersistenceConfig,
+    library: LibraryConfig,
 }
 
 impl SecureConfig {
     pub fn get_cookie_key(&self) -> Key {
         Key::from(&self.key)
     }
+    pub fn simulation(&self) -> &SimulationConfig {
+        &self.simulation
+    }
+    pub fn world(&self) -> &WorldConfig {
+        &self.world
+    }
+    pub fn persistence(&self) -> &PersistenceConfig {
+        &self.persistence
+    }
+    pub fn library(&self) -> &LibraryConfig {
+        &self.library
+    }
 }
 
 impl TryFrom<Config> for SecureConfig {
     type Error = SecureConfigError;
 
     fn try_from(config: Config) -> Result<Self, Self::Error> {
+        let simulation = config.simulation;
+        let world = config.world;
+        let persistence = config.persistence;
+        let library = config.library;
+        // Timers with a period of 0 would panic as soon as they start
+        if simulation.tick_ms == 0 {
+            return Err(SecureConfigError::ZeroPeriod("simulation.tick_ms"));
+        }
+        if persistence.interval_secs == 0 {
+            return Err(SecureConfigError::ZeroPeriod("persistence.interval_secs"));
+        }
+        // A misspelt instruction would silently cost the default
+        if let Some(mnemonic) = simulation
+            .costs
+            .instructions
+            .keys()
+            .find(|mnemonic| !asm::is_instruction(mnemonic))
+        {
+            return Err(SecureConfigError::UnknownInstruction(mnemonic.clone()));
+        }
         // Check if there is an existing key
         if let Some(key) = config.key {
             // Decode key from hex
             let key = hex::decode(key).map_err(SecureConfigError::HexDecodeFailure)?;
             // Ensure the length of the key is greater than or equal to 64 bytes
             if key.len() >= KEY_MIN_LEN {
-                Ok(Self { key })
+                Ok(Self {
+                    key,
+                    simulation,
+                    world,
+                    persistence,
+                    library,
+                })
             } else {
                 Err(SecureConfigError::KeyTooSmall {
                     given: key.len(),
@@ -77,7 +262,13 @@ impl TryFrom<Config> for SecureConfig {
             // Generate a key
             let mut key = vec![0; KEY_MIN_LEN];
             OsRng.fill_bytes(&mut key);
-            Ok(Self { key })
+            Ok(Self {
+                key,
+                simulation,
+                world,
+                persistence,
+                library,
+            })
         }
     }
 }
@@ -85,7 +276,14 @@ impl TryFrom<Config> for SecureConfig {
 #[derive(Debug)]
 pub enum SecureConfigError {
     HexDecodeFailure(FromHexError),
-    KeyTooSmall { given: usize, minimum: usize },
+    KeyTooSmall {
+        given: usize,
+        minimum: usize,
+    },
+    /// The named setting is a period of time that was set to 0
+    ZeroPeriod(&'static str),
+    /// An instruction cost was given for something that isn't an instruction
+    UnknownInstruction(String),
 }
 
 impl fmt::Display for SecureConfigError {
@@ -100,9 +298,72 @@ impl fmt::Display for SecureConfigError {
                     "Given key is too small. Given: {}, minimum: {}",
                     given, minimum
                 ),
+                ZeroPeriod(setting) => format!("{} must be more than 0", setting),
+                UnknownInstruction(mnemonic) => format!(
+                    "simulation.costs.instructions has a cost for {}, which isn't an instruction",
+                    mnemonic
+                ),
             }
         )
     }
 }
 
 impl std::error::Error for SecureConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_periods_are_rejected() {
+        let mut config = Config::default();
+        config.simulation.tick_ms = 0;
+        let err = SecureConfig::try_from(config).err().unwrap();
+        assert_eq!(err.to_string(), "simulation.tick_ms must be more than 0");
+
+        let mut config = Config::default();
+        config.persistence.interval_secs = 0;
+        let err = SecureConfig::try_from(config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "persistence.interval_secs must be more than 0"
+        );
+
+        assert!(SecureConfig::try_from(Config::default()).is_ok());
+    }
+
+    #[test]
+    fn costs_are_only_for_instructions() {
+        let mut config = Config::default();
+        let cost = config.simulation.costs.default;
+        config
+            .simulation
+            .costs
+            .instructions
+            .insert("fwdd".into(), cost);
+        let err = SecureConfig::try_from(config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "simulation.costs.instructions has a cost for fwdd, which isn't an instruction"
+        );
+
+        for mnemonic in &["jz", "stac_s", "mod", "trap"] {
+            let mut config = Config::default();
+            config
+                .simulation
+                .costs
+                .instructions
+                .insert(mnemonic.to_string(), cost);
+            assert!(SecureConfig::try_from(config).is_ok(), "{}", mnemonic);
+        }
+        for keyword in &["let", ".macro"] {
+            let mut config = Config::default();
+            config
+                .simulation
+                .costs
+                .instructions
+                .insert(keyword.to_string(), cost);
+            assert!(SecureConfig::try_from(config).is_err(), "{}", keyword);
+        }
+    }
+}
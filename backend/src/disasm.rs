@@ -0,0 +1,229 @@
+use crate::asm::{OpCode, ReadOpCodeError};
+use crate::robot::DATA_MEM_SIZE;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+/// Turns assembled BIOS or program memory back into assembly that `parse_code` accepts
+///
+/// `code` should be just the bytes the assembler emitted, since the zeroes after them would
+/// decode as `add`. Jump targets get labels like `$l_0x1a` and memory operands get `let`
+/// declarations like `$m_0x05` holding their current value in `data_memory`. Declarations start at
+/// `data_start`, which is where the assembler put the first one.
+pub fn disassemble(code: &[u8], data_memory: &[u8; DATA_MEM_SIZE], data_start: usize) -> String {
+    let end = code.len();
+
+    // Decode every instruction, remembering where it started
+    let mut ops = Vec::new();
+    let mut offset = 0;
+    while offset < end {
+        match OpCode::read_from(&code[offset..]) {
+            Ok((op, len)) => {
+                ops.push((offset, Ok(op)));
+                offset += usize::from(len);
+            }
+            Err(err) => {
+                ops.push((offset, Err(err)));
+                offset += 1;
+            }
+        }
+    }
+
+    // Find every address that is jumped to or read from
+    let mut jump_targets = BTreeSet::new();
+    let mut memory_targets = BTreeSet::new();
+    for (_, op) in &ops {
+        match op {
+            Ok(OpCode::Jmp { label })
+            | Ok(OpCode::JmpCondition { label })
+            | Ok(OpCode::Call { label })
+            | Ok(OpCode::Trap { label })
+            | Ok(OpCode::JmpIf { label, .. }) => {
+                jump_targets.insert(*label);
+            }
+            Ok(OpCode::MovAddr { from: address, .. })
+            | Ok(OpCode::CmpCallAddr { component: address }) => {
+                memory_targets.insert(*address);
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    // The assembler hands out data addresses in declaration order, so declare every address up to
+    // the highest one used to keep them in the same place
+    if let Some(&last) = memory_targets.iter().next_back() {
+        for address in data_start..=usize::from(last) {
+            // `last` is a u8, so every address up to it is too
+            let address = address as u8;
+            let value = data_memory[usize::from(address)];
+            if memory_targets.contains(&address) {
+                writeln!(out, "let ${} = {}", memory_label(address), value).unwrap();
+            } else {
+                writeln!(out, "let $m_unused_0x{:02x} = {}", address, value).unwrap();
+            }
+        }
+    }
+    for (offset, op) in &ops {
+        if let Ok(address) = u8::try_from(*offset) {
+            if jump_targets.remove(&address) {
+                writeln!(out, "{}:", jump_label(address)).unwrap();
+            }
+        }
+        match op {
+            Ok(op) => writeln!(out, "{}", format_op(op)).unwrap(),
+            Err(err) => writeln!(out, "{}", format_invalid(*err, code[*offset])).unwrap(),
+        }
+    }
+    // Whatever is left either points past the end of the code or into the middle of an
+    // instruction
+    for address in jump_targets {
+        if usize::from(address) >= end {
+            writeln!(out, "{}:", jump_label(address)).unwrap();
+        } else {
+            writeln!(
+                out,
+                "// ${} points into the middle of an instruction",
+                jump_label(address)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Disassembles the single instruction at `address` in BIOS or program memory, if it's in bounds
+///
+/// Jump targets are named the same way as by `disassemble`.
+pub fn disassemble_at(memory: &[u8], address: usize) -> Option<String> {
+    let byte = *memory.get(address)?;
+    Some(match OpCode::read_from(&memory[address..]) {
+        Ok((op, _)) => format_op(&op),
+        Err(err) => format_invalid(err, byte),
+    })
+}
+
+/// Comment standing in for bytes starting with `byte` that couldn't be decoded
+fn format_invalid(err: ReadOpCodeError, byte: u8) -> String {
+    match err {
+        ReadOpCodeError::InvalidRegister => format!("// invalid register in 0x{:02x}", byte),
+        _ => format!("// invalid byte 0x{:02x}", byte),
+    }
+}
+
+/// Name used for a jump to `address`, without the `$` prefix
+fn jump_label(address: u8) -> String {
+    format!("l_0x{:02x}", address)
+}
+
+/// Name used for data memory at `address`, without the `$` prefix
+fn memory_label(address: u8) -> String {
+    format!("m_0x{:02x}", address)
+}
+
+/// Formats a single instruction in the syntax `AssemblyLine::from_str` accepts
+fn format_op(op: &OpCode<u8>) -> String {
+    use OpCode::*;
+    match op {
+        Add => "add".into(),
+        Sub => "sub".into(),
+        Mul => "mul".into(),
+        Div => "div".into(),
+        Jmp { label } => format!("jmp ${}", jump_label(*label)),
+        JmpCondition { label } => format!("jmpc ${}", jump_label(*label)),
+        MovReg { to, from } => format!("mov {} {}", to, from),
+        MovImm { to, from } => format!("mov {} {}", to, from),
+        MovAddr { to, from } => format!("mov {} ${}", to, memory_label(*from)),
+        StackGet { to } => format!("stac_g {}", to),
+        StackSet { from } => format!("stac_s {}", from),
+        Exec => "exec".into(),
+        Return => "return".into(),
+        CmpCallAddr { component } => format!("cmp_call ${}", memory_label(*component)),
+        CmpCallImm { component } => format!("cmp_call {}", component),
+        Forward => "fwd".into(),
+        Rotate => "rot".into(),
+        Break => "brk".into(),
+        Battery => "bttry".into(),
+        InventoryGet => "inven".into(),
+        InventoryDrop => "drop".into(),
+        InventoryItem => "item".into(),
+        Output => "out".into(),
+        Noop => "noop".into(),
+        Call { label } => format!("call ${}", jump_label(*label)),
+        Trap { label } => format!("trap ${}", jump_label(*label)),
+        Cmp => "cmp".into(),
+        Load { to } => format!("load {}", to),
+        Store { from } => format!("store {}", from),
+        And => "and".into(),
+        Or => "or".into(),
+        Xor => "xor".into(),
+        Not => "not".into(),
+        Shl => "shl".into(),
+        Shr => "shr".into(),
+        Mod => "mod".into(),
+        Arithmetic { op, lhs, rhs } => format!("{} {} {}", op.mnemonic(), lhs, rhs),
+        JmpIf { condition, label } => {
+            format!("{} ${}", condition.mnemonic(), jump_label(*label))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_ok;
+
+    /// Checks that disassembling the code and assembling it again gives the same memory
+    fn assert_round_trips(code: &str, data_start: usize) {
+        let (bios, len, data) = assemble_ok(code, data_start);
+        let disassembly = disassemble(&bios[..len], &data, data_start);
+        let (reassembled, relen, redata) = assemble_ok(&disassembly, data_start);
+        assert_eq!(&reassembled[..relen], &bios[..len], "{}", disassembly);
+        assert_eq!(&redata[..], &data[..], "{}", disassembly);
+    }
+
+    #[test]
+    fn trailing_adds_are_kept() {
+        let (bios, len, data) = assemble_ok("mov %rga 1\nadd", 0);
+        assert_eq!(disassemble(&bios[..len], &data, 0), "mov %rga 1\nadd\n");
+        assert_round_trips("mov %rga 1\nadd", 0);
+        assert_round_trips("add\nadd\nadd", 0);
+    }
+
+    #[test]
+    fn labels_round_trip() {
+        assert_round_trips(
+            "top:\nfwd\njz $top\ncall $end\nand %rgc 3\njmp $top\nend:",
+            0,
+        );
+    }
+
+    #[test]
+    fn declarations_round_trip() {
+        assert_round_trips(
+            "let $a = 3\nlet $unused = 4\nlet $b = 5\nmov %rga $b\ncmp_call $a\nmov %rgb $b",
+            0,
+        );
+    }
+
+    #[test]
+    fn program_declarations_start_after_the_bios_data() {
+        let (progmem, len, data) = assemble_ok("let $a = 3\nlet $b = 5\nmov %rga $b", 2);
+        assert_eq!(
+            disassemble(&progmem[..len], &data, 2),
+            "let $m_unused_0x02 = 3\nlet $m_0x03 = 5\nmov %rga $m_0x03\n"
+        );
+        assert_round_trips("let $a = 3\nlet $unused = 4\nlet $b = 5\ncmp_call $b", 2);
+    }
+
+    #[test]
+    fn labels_into_the_middle_of_an_instruction_are_flagged() {
+        let (mut bios, len, data) = assemble_ok("jmp $a\nmov %rga 1\na:", 0);
+        // Point the jump at the operand of the mov
+        bios[1] = 3;
+        assert_eq!(
+            disassemble(&bios[..len], &data, 0),
+            "jmp $l_0x03\nmov %rga 1\n// $l_0x03 points into the middle of an instruction\n"
+        );
+    }
+}
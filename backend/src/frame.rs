@@ -0,0 +1,166 @@
+//! Binary websocket frames for map data
+//!
+//! Every frame starts with a 16 byte header, with multi-byte values in big endian:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 1    | format version, currently `FRAME_VERSION` |
+//! | 1      | 1    | frame type, one of the `FRAME_*` types  |
+//! | 2      | 1    | flags, see `FLAG_RLE`                   |
+//! | 3      | 1    | reserved, always 0                      |
+//! | 4      | 4    | x of the viewport origin                |
+//! | 8      | 4    | y of the viewport origin                |
+//! | 12     | 2    | viewport width                          |
+//! | 14     | 2    | viewport height                         |
+//!
+//! A `FRAME_MAP_VIEW` body holds an `(id, orient)` pair for every tile in the viewport, one column
+//! after another. With `FLAG_RLE` set, it instead holds `(count, id, orient)` runs that add up to
+//! the same tiles.
+//!
+//! A `FRAME_MAP_UPDATE` body holds a 2 byte count followed by that many `(dx, dy, id, orient)`
+//! entries, where `dx` and `dy` are 2 bytes each and relative to the viewport origin.
+use crate::world::Viewport;
+use std::convert::TryFrom;
+
+pub const FRAME_VERSION: u8 = 1;
+
+/// Frame holding every tile in a viewport
+pub const FRAME_MAP_VIEW: u8 = 0;
+/// Frame holding the tiles in a viewport that changed
+pub const FRAME_MAP_UPDATE: u8 = 1;
+
+/// The body is run-length encoded
+pub const FLAG_RLE: u8 = 0b0000_0001;
+
+const HEADER_LEN: usize = 16;
+
+/// Writes the frame header
+fn header(frame_type: u8, flags: u8, viewport: &Viewport) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN);
+    frame.push(FRAME_VERSION);
+    frame.push(frame_type);
+    frame.push(flags);
+    frame.push(0);
+    frame.extend_from_slice(&saturate_u32(viewport.x()).to_be_bytes());
+    frame.extend_from_slice(&saturate_u32(viewport.y()).to_be_bytes());
+    frame.extend_from_slice(&saturate_u16(viewport.width()).to_be_bytes());
+    frame.extend_from_slice(&saturate_u16(viewport.height()).to_be_bytes());
+    frame
+}
+
+fn saturate_u32(value: usize) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+fn saturate_u16(value: usize) -> u16 {
+    u16::try_from(value).unwrap_or(u16::MAX)
+}
+
+/// Builds a frame for the output of `TileMap::gen_view`, run-length encoding it if that is smaller
+pub fn map_view(viewport: &Viewport, tiles: &[u8]) -> Vec<u8> {
+    let runs = run_length_encode(tiles);
+    let (flags, body) = if runs.len() < tiles.len() {
+        (FLAG_RLE, runs.as_slice())
+    } else {
+        (0, tiles)
+    };
+    let mut frame = header(FRAME_MAP_VIEW, flags, viewport);
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Builds a frame for tiles that changed within a viewport, given as (x, y, id, orient) in world
+/// coordinates
+pub fn map_update(viewport: &Viewport, tiles: &[(usize, usize, u8, u8)]) -> Vec<u8> {
+    let mut frame = header(FRAME_MAP_UPDATE, 0, viewport);
+    let count = saturate_u16(tiles.len());
+    frame.extend_from_slice(&count.to_be_bytes());
+    for &(x, y, id, orient) in tiles.iter().take(usize::from(count)) {
+        frame.extend_from_slice(&saturate_u16(x - viewport.x()).to_be_bytes());
+        frame.extend_from_slice(&saturate_u16(y - viewport.y()).to_be_bytes());
+        frame.push(id);
+        frame.push(orient);
+    }
+    frame
+}
+
+/// Turns (id, orient) pairs into (count, id, orient) runs
+fn run_length_encode(tiles: &[u8]) -> Vec<u8> {
+    let mut runs: Vec<u8> = Vec::new();
+    for pair in tiles.chunks_exact(2) {
+        let len = runs.len();
+        if len >= 3 && runs[len - 3] < u8::MAX && runs[len - 2..] == *pair {
+            runs[len - 3] += 1;
+        } else {
+            runs.push(1);
+            runs.extend_from_slice(pair);
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::TileMap;
+
+    #[test]
+    fn header_layout() {
+        let map = TileMap::new(1, 1);
+        let view = map.viewport(0x0102_0304, 5, 0x0607, 8);
+        assert_eq!(
+            header(FRAME_MAP_VIEW, FLAG_RLE, &view),
+            vec![
+                FRAME_VERSION,
+                FRAME_MAP_VIEW,
+                FLAG_RLE,
+                0,
+                1,
+                2,
+                3,
+                4,
+                0,
+                0,
+                0,
+                5,
+                6,
+                7,
+                0,
+                8
+            ]
+        );
+    }
+
+    #[test]
+    fn repetitive_views_are_run_length_encoded() {
+        let map = TileMap::new(4, 4);
+        let view = map.viewport(0, 0, 4, 4);
+        let frame = map_view(&view, &map.gen_view(&view));
+        assert_eq!(frame[2], FLAG_RLE);
+        assert_eq!(&frame[HEADER_LEN..], &[16, 0, 0]);
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let tiles = vec![2; 600];
+        assert_eq!(run_length_encode(&tiles), vec![255, 2, 2, 45, 2, 2]);
+    }
+
+    #[test]
+    fn varied_views_are_sent_raw() {
+        let map = TileMap::new(1, 1);
+        let view = map.viewport(0, 0, 2, 1);
+        let frame = map_view(&view, &[1, 0, 2, 0]);
+        assert_eq!(frame[2], 0);
+        assert_eq!(&frame[HEADER_LEN..], &[1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn updates_are_relative_to_the_viewport() {
+        let map = TileMap::new(1, 1);
+        let view = map.viewport(10, 20, 5, 5);
+        let frame = map_update(&view, &[(12, 21, 1, 3)]);
+        assert_eq!(frame[1], FRAME_MAP_UPDATE);
+        assert_eq!(&frame[HEADER_LEN..], &[0, 1, 0, 2, 0, 1, 1, 3]);
+    }
+}
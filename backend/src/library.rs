@@ -0,0 +1,48 @@
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Extension of the files in the library directory
+const SNIPPET_EXTENSION: &str = "asm";
+
+/// Named snippets of assembly that uploaded code can pull in with `.include "name"`
+#[derive(Default)]
+pub struct Library {
+    snippets: HashMap<String, String>,
+}
+
+impl Library {
+    /// Loads every `.asm` file in `dir`, named after the file, or an empty library if there's no
+    /// such directory
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let mut library = Self::default();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(library),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SNIPPET_EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                library.insert(name, fs::read_to_string(&path)?);
+            }
+        }
+        info!("Loaded {} library snippets", library.snippets.len());
+        Ok(library)
+    }
+
+    /// Adds a snippet, replacing any with the same name
+    pub fn insert(&mut self, name: &str, code: String) {
+        self.snippets.insert(name.to_lowercase(), code);
+    }
+
+    /// Finds the code of the snippet called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.snippets.get(name).map(String::as_str)
+    }
+}
@@ -0,0 +1,279 @@
+use crate::api::{CodeWarning, Severity};
+use crate::asm::{ArithOp, AssemblyLine, Location, OpCode, Operand, Register};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Something in otherwise valid code that probably isn't what the player meant
+#[derive(Debug, Serialize)]
+pub enum Lint {
+    /// A label that nothing jumps to
+    UnusedLabel { name: String },
+    /// A memory label that nothing reads
+    UnusedMemory { name: String },
+    /// An instruction right after an unconditional `jmp` with no label to reach it
+    UnreachableCode,
+    /// A `jmp` back to a label with no `bttry` or way out in between, which loops until the battery
+    /// is flat
+    SelfJump { name: String },
+    /// A `div` or `mod` by 0, or by `%rgb` when it was just set to 0
+    DivideByZero,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Lint::*;
+        match self {
+            UnusedLabel { name } => write!(f, "label ${} is never jumped to", name),
+            UnusedMemory { name } => write!(f, "memory ${} is never read", name),
+            UnreachableCode => write!(f, "code after an unconditional jmp can never run"),
+            SelfJump { name } => write!(
+                f,
+                "jmp ${} loops forever without checking the battery",
+                name
+            ),
+            DivideByZero => write!(
+                f,
+                "the divisor is always 0 here, so dividing by it will fault"
+            ),
+        }
+    }
+}
+
+impl CodeWarning {
+    fn new(line: usize, warning: Lint) -> Self {
+        Self {
+            line,
+            severity: Severity::Warning,
+            message: warning.to_string(),
+            warning,
+        }
+    }
+}
+
+/// Finds likely mistakes in parsed code, without stopping it from being uploaded
+///
+/// `code` is expected to be in order, with the location of each line as returned by `parse_code`.
+/// Warnings are given on lines of the uploaded code.
+pub fn lint(code: &[(Location, AssemblyLine)]) -> Vec<CodeWarning> {
+    let mut warnings = Vec::new();
+
+    // Find every label that's jumped to and every memory label that's read
+    let mut jumped_to = HashSet::new();
+    let mut read = HashSet::new();
+    for (_, line) in code {
+        match line {
+            AssemblyLine::Op(OpCode::Jmp { label })
+            | AssemblyLine::Op(OpCode::JmpCondition { label })
+            | AssemblyLine::Op(OpCode::Call { label })
+            | AssemblyLine::Op(OpCode::Trap { label })
+            | AssemblyLine::Op(OpCode::JmpIf { label, .. }) => {
+                jumped_to.insert(label.name());
+            }
+            AssemblyLine::Op(OpCode::MovAddr { from, .. }) => {
+                read.insert(from.name());
+            }
+            AssemblyLine::Op(OpCode::CmpCallAddr { component }) => {
+                read.insert(component.name());
+            }
+            _ => {}
+        }
+    }
+
+    // Whether %rgb is known to hold 0 at this point
+    let mut rgb_zero = false;
+    for (index, (location, line)) in code.iter().enumerate() {
+        let line_num = location.upload_line;
+        match line {
+            AssemblyLine::Label { name } => {
+                if !jumped_to.contains(name.name()) {
+                    warnings.push(CodeWarning::new(
+                        line_num,
+                        Lint::UnusedLabel {
+                            name: name.name().to_string(),
+                        },
+                    ));
+                }
+                // Anything could jump here
+                rgb_zero = false;
+            }
+            AssemblyLine::MemoryDeclaration { label, .. } => {
+                if !read.contains(label.name()) {
+                    warnings.push(CodeWarning::new(
+                        line_num,
+                        Lint::UnusedMemory {
+                            name: label.name().to_string(),
+                        },
+                    ));
+                }
+            }
+            AssemblyLine::Op(op) => {
+                match op.clone().expand_shorthand() {
+                    OpCode::Arithmetic {
+                        op: ArithOp::Div | ArithOp::Mod,
+                        rhs,
+                        ..
+                    } if rhs == Operand::Immediate(0)
+                        || (rhs == Operand::Register(Register::RGB) && rgb_zero) =>
+                    {
+                        warnings.push(CodeWarning::new(line_num, Lint::DivideByZero))
+                    }
+                    OpCode::Jmp { label } => {
+                        if let Some(warning) = self_jump(&code[..index], label.name()) {
+                            warnings.push(CodeWarning::new(line_num, warning));
+                        }
+                        if let Some(next) = unreachable_after(&code[index + 1..]) {
+                            warnings.push(CodeWarning::new(next, Lint::UnreachableCode));
+                        }
+                    }
+                    _ => {}
+                }
+                rgb_zero = match op {
+                    OpCode::MovImm {
+                        to: Register::RGB,
+                        from,
+                    } => *from == 0,
+                    OpCode::MovReg {
+                        to: Register::RGB, ..
+                    }
+                    | OpCode::MovAddr {
+                        to: Register::RGB, ..
+                    }
+                    | OpCode::StackGet { to: Register::RGB }
+                    | OpCode::Load { to: Register::RGB } => false,
+                    // These can run code that might change any register
+                    OpCode::Exec
+                    | OpCode::Call { .. }
+                    | OpCode::CmpCallAddr { .. }
+                    | OpCode::CmpCallImm { .. } => false,
+                    _ => rgb_zero,
+                };
+            }
+            AssemblyLine::EmptyLine => {}
+        }
+    }
+    warnings.sort_by_key(|warning| warning.line);
+    warnings
+}
+
+/// Checks if a `jmp` goes back to a label in the code `before` it without checking the battery
+///
+/// Loops that can leave through a conditional jump, `call` or `return` are fine.
+fn self_jump(before: &[(Location, AssemblyLine)], target: &str) -> Option<Lint> {
+    for (_, line) in before.iter().rev() {
+        match line {
+            AssemblyLine::Label { name } if name.name() == target => {
+                return Some(Lint::SelfJump {
+                    name: target.to_string(),
+                });
+            }
+            AssemblyLine::Op(
+                OpCode::Battery
+                | OpCode::JmpCondition { .. }
+                | OpCode::JmpIf { .. }
+                | OpCode::Call { .. }
+                | OpCode::Return,
+            ) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Finds the line of the first instruction in the code `after` a `jmp`, if it can't be reached
+fn unreachable_after(after: &[(Location, AssemblyLine)]) -> Option<usize> {
+    for (location, line) in after {
+        match line {
+            AssemblyLine::Label { .. } => return None,
+            AssemblyLine::Op(_) => return Some(location.upload_line),
+            AssemblyLine::EmptyLine | AssemblyLine::MemoryDeclaration { .. } => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parse_code;
+    use crate::library::Library;
+
+    /// Lints code that should parse, giving the line and message of every warning
+    fn lint_lines(code: &str) -> Vec<(usize, String)> {
+        let code = parse_code(code.into(), &Library::default()).ok().unwrap();
+        lint(&code)
+            .into_iter()
+            .map(|warning| (warning.line, warning.message))
+            .collect()
+    }
+
+    #[test]
+    fn clean_code_has_no_warnings() {
+        let code =
+            "let $x = 1\nstart:\nbttry\nmov %rga $x\nmov %rgb 2\ndiv\njmpc $start\njmp $start\n";
+        assert_eq!(lint_lines(code), vec![]);
+    }
+
+    #[test]
+    fn unused_labels_and_memory() {
+        assert_eq!(
+            lint_lines("a:\nlet $x = 1\nnoop\n"),
+            vec![
+                (0, "label $a is never jumped to".to_string()),
+                (1, "memory $x is never read".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn code_after_jmp_is_unreachable() {
+        let code = "a:\nbttry\njmp $a\n\nlet $x = 1\nmov %rga $x\nb:\njmp $b\n";
+        let warnings = lint_lines(code);
+        assert!(warnings.contains(&(5, "code after an unconditional jmp can never run".into())));
+        assert_eq!(warnings.iter().filter(|(line, _)| *line == 6).count(), 0);
+    }
+
+    #[test]
+    fn self_jump_without_battery_check() {
+        assert_eq!(
+            lint_lines("a:\nfwd\njmp $a\n"),
+            vec![(
+                2,
+                "jmp $a loops forever without checking the battery".into()
+            )]
+        );
+        assert_eq!(lint_lines("a:\nbttry\nfwd\njmp $a\n"), vec![]);
+        // Loops with a way out don't run forever
+        assert_eq!(
+            lint_lines("a:\nfwd\njz $out\njmp $a\nout:\nreturn\n"),
+            vec![]
+        );
+        assert_eq!(
+            lint_lines("a:\nfwd\njmpc $out\njmp $a\nout:\nreturn\n"),
+            vec![]
+        );
+        assert_eq!(lint_lines("a:\ncall $f\njmp $a\nf:\nreturn\n"), vec![]);
+        // Jumping forwards isn't a loop
+        assert_eq!(lint_lines("jmp $a\na:\nnoop\n"), vec![]);
+    }
+
+    #[test]
+    fn divide_by_immediate_zero() {
+        assert_eq!(
+            lint_lines("mov %rgb 0\nmov %rga 4\ndiv\n"),
+            vec![(
+                2,
+                "the divisor is always 0 here, so dividing by it will fault".into()
+            )]
+        );
+        assert_eq!(
+            lint_lines("mod %rgc 0\nmod %rgc %rgb\n"),
+            vec![(
+                0,
+                "the divisor is always 0 here, so dividing by it will fault".into()
+            )]
+        );
+        assert_eq!(lint_lines("mov %rgb 0\nmov %rgb 2\ndiv\n"), vec![]);
+        assert_eq!(lint_lines("mov %rgb 0\na:\ndiv\njmpc $a\n"), vec![]);
+    }
+}
@@ -1,27 +1,37 @@
 mod api;
 mod asm;
 mod config;
+mod disasm;
+mod frame;
+mod library;
+mod lint;
+mod persist;
 mod robot;
+mod sim;
 mod user;
+mod world;
 
-use crate::api::{CodeError, Request as ApiRequest, Response as ApiResponse};
-use crate::asm::AssemblyLine;
-use crate::config::{Config, SecureConfig};
+use crate::api::{Request as ApiRequest, Response as ApiResponse};
+use crate::config::{Config, SecureConfig, WorldConfig};
+use crate::library::Library;
+use crate::persist::Snapshot;
 use crate::user::User;
+use crate::world::{TileMap, Viewport};
 use cookie::{Cookie, CookieJar, Key};
-use futures_channel::mpsc::UnboundedSender;
-use futures_util::{SinkExt, StreamExt};
+use futures_channel::mpsc::{unbounded, UnboundedSender};
+use futures_util::StreamExt;
 use http::header::{HeaderValue, COOKIE, SET_COOKIE};
 use http::status::StatusCode;
 use log::{debug, error, info, warn};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::env;
 use std::net::SocketAddr;
-use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_tungstenite::tungstenite::handshake::server::{
     Callback, ErrorResponse, Request, Response,
 };
@@ -29,21 +39,62 @@ use tokio_tungstenite::tungstenite::protocol::Message;
 
 type Tx = UnboundedSender<Message>;
 
+/// Largest width or height a client can view at once
+const MAX_VIEWPORT_LEN: usize = 128;
+
+/// A connected client
+struct Peer {
+    /// Id of the user logged in on this connection
+    user_id: usize,
+    /// Queue of messages to send to the client
+    tx: Tx,
+    /// Part of the world the client is looking at
+    viewport: Option<Viewport>,
+}
+
+impl Peer {
+    /// Serializes a response and queues it to be sent to the client
+    fn send(&self, response: &ApiResponse) {
+        // Serialize response as json
+        let response_text = serde_json::to_string(response).unwrap();
+        if let Err(err) = self.tx.unbounded_send(Message::Text(response_text)) {
+            error!("Error sending response to client: {}", err)
+        }
+    }
+
+    /// Queues a binary frame to be sent to the client
+    fn send_frame(&self, frame: Vec<u8>) {
+        if let Err(err) = self.tx.unbounded_send(Message::Binary(frame)) {
+            error!("Error sending frame to client: {}", err)
+        }
+    }
+}
+
 #[derive(Clone)]
 struct ServerState {
-    peer_map: Arc<Mutex<HashMap<SocketAddr, Tx>>>,
+    peer_map: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
     cookie_jar: Arc<ThreadPrivateJar>,
     // TODO: make this fnv hashmap
     // TODO: better sync primitive
     users: Arc<RwLock<HashMap<usize, User>>>,
+    world: Arc<RwLock<TileMap>>,
+    /// Snippets that uploaded code can include
+    library: Arc<Library>,
 }
 
 impl ServerState {
-    fn new(key: Key) -> Self {
+    fn new(key: Key, world_config: &WorldConfig, library: Library) -> Self {
+        // Create a walled-in world for the robots to live in
+        let mut world = TileMap::new(world_config.width, world_config.height);
+        world.generate_box_corner();
+        info!("Generated a {}x{} world", world.width(), world.height());
+
         Self {
             peer_map: Arc::new(Mutex::new(HashMap::new())),
             cookie_jar: Arc::new(ThreadPrivateJar::new(key)),
             users: Arc::new(RwLock::new(HashMap::new())),
+            world: Arc::new(RwLock::new(world)),
+            library: Arc::new(library),
         }
     }
 }
@@ -81,6 +132,8 @@ impl CookieHandler {
 }
 
 impl CookieHandler {
+    // The error type is fixed by tungstenite's handshake callback
+    #[allow(clippy::result_large_err)]
     fn new_cookie_response(
         &mut self,
         response: Response,
@@ -142,16 +195,17 @@ impl CookieHandler {
             }
             Err(err) => {
                 error!("Error generating cookie header: {}", err);
-                return Err(Response::builder()
+                Err(Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(None)
-                    .unwrap());
+                    .unwrap())
             }
         }
     }
 }
 
 impl Callback for &mut CookieHandler {
+    #[allow(clippy::result_large_err)]
     fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
         // Get one reference to the jar
         let jar = self.jar.clone();
@@ -170,9 +224,12 @@ impl Callback for &mut CookieHandler {
                             // Check to see if the cookie sent is authenticated with our key
                             match jar.jar.lock() {
                                 Ok(mut jar) => {
+                                    // Put the client's cookie in the jar so it gets decrypted,
+                                    // which also works for cookies from before a restart
+                                    jar.add_original(cookie.clone().into_owned());
                                     // Open the jar using the private key
                                     let private_jar = jar.private(&self.jar.key);
-                                    if let Some(jar_cookie) = private_jar.get(&cookie_name) {
+                                    if let Some(jar_cookie) = private_jar.get(cookie_name) {
                                         debug!(
                                             "Cookie in jar for {} is {}",
                                             cookie_name,
@@ -230,7 +287,71 @@ impl Callback for &mut CookieHandler {
     }
 }
 
-async fn handle_connection(mut state: ServerState, raw_stream: TcpStream, addr: SocketAddr) {
+/// Assembles code into a user's robot, either as its BIOS or as the program the BIOS can `exec`
+fn upload_code(state: &ServerState, user_id: usize, code: String, program: bool) -> ApiResponse {
+    let code = match asm::parse_code(code, &state.library) {
+        Ok(code) => code,
+        Err(errors) => {
+            return ApiResponse::UploadCode {
+                success: false,
+                errors: Some(errors),
+                assembly_errors: Vec::new(),
+                warnings: Vec::new(),
+                disassembly: None,
+            }
+        }
+    };
+    let warnings = lint::lint(&code);
+    // Hold on to the user for the whole upload so the world clock never sees a half-loaded robot
+    let mut users = state.users.write().unwrap();
+    let robot = &mut users.get_mut(&user_id).unwrap().robot;
+    // Assemble into copies so a failed upload leaves the robot alone. Programs keep their
+    // declarations after the BIOS's.
+    let mut assembled = [0; robot::BIOS_MEM_SIZE];
+    let mut memory = robot.memory;
+    let data_start = if program { robot.bios_data_len() } else { 0 };
+    let assembly_errors = asm::assemble(&code, &mut assembled, &mut memory, data_start);
+    let success = assembly_errors.is_empty();
+    if success && program {
+        robot.load_program(
+            assembled,
+            asm::code_len(&code),
+            memory,
+            asm::data_len(&code),
+        );
+    } else if success {
+        robot.load_bios(
+            assembled,
+            asm::code_len(&code),
+            memory,
+            asm::data_len(&code),
+        );
+    }
+    // A failed upload left the old code in place, which isn't what was sent
+    let disassembly = if !success {
+        None
+    } else if program {
+        Some(disasm::disassemble(
+            robot.program_code(),
+            &robot.memory,
+            data_start,
+        ))
+    } else {
+        Some(disasm::disassemble(robot.bios_code(), &robot.memory, 0))
+    };
+    if let Some(disassembly) = &disassembly {
+        debug!("Robot code:\n{}", disassembly);
+    }
+    ApiResponse::UploadCode {
+        success,
+        errors: None,
+        assembly_errors,
+        warnings,
+        disassembly,
+    }
+}
+
+async fn handle_connection(state: ServerState, raw_stream: TcpStream, addr: SocketAddr) {
     println!("Incoming TCP connection from: {}", addr);
     // Get jar
     let jar = state.cookie_jar.clone();
@@ -249,7 +370,29 @@ async fn handle_connection(mut state: ServerState, raw_stream: TcpStream, addr:
     };
     info!("User logged in from {} with id {}", addr, user_id);
 
-    let (mut outgoing, mut incoming) = ws_stream.split();
+    // Find the user's robot, or give them a new one
+    {
+        let mut users = state.users.write().unwrap();
+        if let Entry::Vacant(entry) = users.entry(user_id) {
+            let mut user = User::new();
+            if !user.robot.spawn(&mut state.world.write().unwrap()) {
+                warn!("No room in the world for the robot of user {}", user_id);
+            }
+            entry.insert(user);
+            info!("Created a robot for user {}", user_id);
+        }
+    }
+
+    let (outgoing, mut incoming) = ws_stream.split();
+    // Queue messages through a channel so the world clock can send to this client too
+    let (tx, rx) = unbounded();
+    tokio::spawn(rx.map(Ok).forward(outgoing));
+    let peer = Peer {
+        user_id,
+        tx,
+        viewport: None,
+    };
+    state.peer_map.lock().unwrap().insert(addr, peer);
 
     while let Some(message) = incoming.next().await {
         match message {
@@ -259,36 +402,27 @@ async fn handle_connection(mut state: ServerState, raw_stream: TcpStream, addr:
                 // Handle the code
                 match message {
                     Ok(ApiRequest::UploadCode(code)) => {
-                        // Extract into parts for a request
-                        let (_code, response) = match asm::parse_code(code) {
-                            Ok(code) => (
-                                Some(code),
-                                ApiResponse::UploadCode {
-                                    success: true,
-                                    errors: None,
-                                },
-                            ),
-                            Err(errors) => (
-                                None,
-                                ApiResponse::UploadCode {
-                                    success: false,
-                                    errors: Some(errors),
-                                },
-                            ),
-                        };
-                        // Serialize response as json
-                        let response_text = serde_json::to_string(&response).unwrap();
-                        // Send response
-                        if let Err(err) = outgoing.send(Message::Text(response_text)).await {
-                            error!("Error sending response to client: {}", err)
+                        let response = upload_code(&state, user_id, code, false);
+                        if let Some(peer) = state.peer_map.lock().unwrap().get(&addr) {
+                            peer.send(&response);
                         }
-                        // Try to parse the code because why not
-                        let mut robot = robot::Robot::default();
-                        if let Some(_code) = _code {
-                            let assembly_result =
-                                asm::assemble(&_code, &mut robot.bios, &mut robot.memory);
-                            dbg!(&assembly_result);
-                            println!("{:?}", robot.bios.to_vec());
+                    }
+                    Ok(ApiRequest::UploadProgram(code)) => {
+                        let response = upload_code(&state, user_id, code, true);
+                        if let Some(peer) = state.peer_map.lock().unwrap().get(&addr) {
+                            peer.send(&response);
+                        }
+                    }
+                    Ok(ApiRequest::SetViewport { x, y, w, h }) => {
+                        let w = w.min(MAX_VIEWPORT_LEN);
+                        let h = h.min(MAX_VIEWPORT_LEN);
+                        // Send the whole view, and later only the tiles that change in it
+                        let world = state.world.read().unwrap();
+                        let viewport = world.viewport(x, y, w, h);
+                        let frame = frame::map_view(&viewport, &world.gen_view(&viewport));
+                        if let Some(peer) = state.peer_map.lock().unwrap().get_mut(&addr) {
+                            peer.send_frame(frame);
+                            peer.viewport = Some(viewport);
                         }
                     }
                     Err(err) => {
@@ -303,15 +437,17 @@ async fn handle_connection(mut state: ServerState, raw_stream: TcpStream, addr:
                 warn!("Received unhandled binary data: {:?}", data);
             }
             Ok(Message::Ping(data)) => {
-                if let Err(err) = outgoing.send(Message::Pong(data)).await {
-                    error!("Error sending pong: {}", err);
+                if let Some(peer) = state.peer_map.lock().unwrap().get(&addr) {
+                    if let Err(err) = peer.tx.unbounded_send(Message::Pong(data)) {
+                        error!("Error sending pong: {}", err);
+                    }
                 }
             }
             Ok(Message::Pong(_data)) => {
                 // Do nothing
             }
             Ok(Message::Close(_)) => {
-                return;
+                break;
             }
             Err(err) => {
                 error!("Error reading incoming message: {}", err);
@@ -319,7 +455,7 @@ async fn handle_connection(mut state: ServerState, raw_stream: TcpStream, addr:
         }
     }
     info!("{} disconnected", &addr);
-    //state.peer_map.as_ref().lock().unwrap().remove(&addr);
+    state.peer_map.lock().unwrap().remove(&addr);
 }
 
 #[tokio::main]
@@ -340,18 +476,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Load the cookie key
     let cookie_key = config.get_cookie_key();
 
+    // Load the snippets uploaded code can include
+    let library = Library::load(&config.library().path)?;
+
     // Create an object for shared state
-    let state = ServerState::new(cookie_key);
+    let state = ServerState::new(cookie_key, config.world(), library);
+
+    // Bring back the users and robots from the last run
+    Snapshot::load(&config.persistence().path)?.restore(&state)?;
+
+    // Start the world clock
+    tokio::spawn(sim::run(state.clone(), config.simulation().clone()));
+    // Save the world every so often
+    tokio::spawn(persist::run(state.clone(), config.persistence().clone()));
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let mut listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
 
-    // Let's spawn the handling of each connection in a separate task.
-    while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(state.clone(), stream, addr));
+    // Service managers like systemd and docker stop the server with SIGTERM rather than ctrl-c
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    // Let's spawn the handling of each connection in a separate task, until we're told to stop
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    tokio::spawn(handle_connection(state.clone(), stream, addr));
+                }
+                Err(_) => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Terminated, shutting down");
+                break;
+            }
+        }
     }
 
+    // Save the world one last time
+    persist::save(&state, config.persistence());
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uploads code for a fresh user, giving whether it worked and the disassembly sent back
+    fn upload(state: &ServerState, code: &str, program: bool) -> (bool, Option<String>) {
+        match upload_code(state, 0, code.into(), program) {
+            ApiResponse::UploadCode {
+                success,
+                disassembly,
+                ..
+            } => (success, disassembly),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn failed_uploads_have_no_disassembly() {
+        let state = ServerState::new(
+            Key::from(&[0; 64]),
+            &WorldConfig::default(),
+            Library::default(),
+        );
+        state.users.write().unwrap().insert(0, User::new());
+        assert_eq!(upload(&state, "exec", false), (true, Some("exec\n".into())));
+        assert_eq!(upload(&state, "out", true), (true, Some("out\n".into())));
+        // Jumping to a label that doesn't exist only fails once assembling
+        assert_eq!(upload(&state, "jmp $nowhere", true), (false, None));
+        assert_eq!(upload(&state, "jmp $nowhere", false), (false, None));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::PersistenceConfig;
+use crate::robot::RobotSnapshot;
+use crate::user::User;
+use crate::ServerState;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time;
+
+/// Everything that is kept across server restarts
+#[derive(Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    users: Vec<UserSnapshot>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct UserSnapshot {
+    id: usize,
+    robot: RobotSnapshot,
+}
+
+impl Snapshot {
+    /// Captures the current users and their robots
+    pub fn take(state: &ServerState) -> Result<Self, Box<dyn std::error::Error>> {
+        let users = state
+            .users
+            .read()
+            .map_err(|err| format!("Error getting access to users: {}", err))?;
+        let mut users: Vec<_> = users
+            .iter()
+            .map(|(id, user)| UserSnapshot {
+                id: *id,
+                robot: RobotSnapshot::from(&user.robot),
+            })
+            .collect();
+        users.sort_by_key(|user| user.id);
+
+        Ok(Self { users })
+    }
+
+    /// Loads a snapshot, or an empty one if nothing has been saved yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        // Read the entire file
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+        // Parse the file as json
+        let snapshot = serde_json::from_slice(&data)?;
+
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        // Serialize to bytes
+        let ser = serde_json::to_vec(self)?;
+        // Write to a temporary file first so a crash can't leave half a snapshot behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &ser)?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Puts the saved users and robots into the server state
+    pub fn restore(self, state: &ServerState) -> Result<(), Box<dyn std::error::Error>> {
+        let mut users = state
+            .users
+            .write()
+            .map_err(|err| format!("Error getting access to users: {}", err))?;
+        let mut world = state
+            .world
+            .write()
+            .map_err(|err| format!("Error getting access to the world: {}", err))?;
+        for saved in self.users {
+            let mut user = User::new();
+            user.robot = saved.robot.into();
+            if !user.robot.place(&mut world) {
+                warn!("No room in the world for the robot of user {}", saved.id);
+            }
+            users.insert(saved.id, user);
+        }
+        // Carry on handing out ids after the highest one that was saved
+        if let Some(max_id) = users.keys().max() {
+            state.cookie_jar.cur_id.store(max_id + 1, Ordering::Relaxed);
+        }
+        info!("Restored {} users", users.len());
+
+        Ok(())
+    }
+}
+
+/// Saves a snapshot of the server state
+pub fn save(state: &ServerState, config: &PersistenceConfig) {
+    match Snapshot::take(state).and_then(|snapshot| snapshot.save(&config.path)) {
+        Ok(()) => info!("Saved snapshot to {}", config.path.display()),
+        Err(err) => error!("Error saving snapshot: {}", err),
+    }
+}
+
+/// Saves a snapshot of the server state every so often, forever
+pub async fn run(state: ServerState, config: PersistenceConfig) {
+    let period = Duration::from_secs(config.interval_secs);
+    // Don't save immediately, there's nothing new yet
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        save(&state, &config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WorldConfig;
+    use crate::library::Library;
+    use crate::robot::Robot;
+    use crate::world::TILE_ROBOT;
+    use cookie::Key;
+
+    fn new_state() -> ServerState {
+        ServerState::new(
+            Key::from(&[0; 64]),
+            &WorldConfig::default(),
+            Library::default(),
+        )
+    }
+
+    /// A saved user whose robot was at (x, y)
+    fn saved_user(id: usize, x: usize, y: usize) -> UserSnapshot {
+        let mut robot = serde_json::to_value(RobotSnapshot::from(&Robot::default())).unwrap();
+        robot["x"] = x.into();
+        robot["y"] = y.into();
+        UserSnapshot {
+            id,
+            robot: serde_json::from_value(robot).unwrap(),
+        }
+    }
+
+    #[test]
+    fn ids_continue_after_the_highest_restored_one() {
+        let state = new_state();
+        let users = vec![saved_user(3, 2, 2), saved_user(7, 3, 2)];
+        Snapshot { users }.restore(&state).unwrap();
+        let mut ids: Vec<_> = state.users.read().unwrap().keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![3, 7]);
+        assert_eq!(state.cookie_jar.cur_id.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn robots_on_taken_tiles_are_spawned_elsewhere() {
+        let state = new_state();
+        let users = vec![saved_user(0, 5, 5), saved_user(1, 5, 5)];
+        Snapshot { users }.restore(&state).unwrap();
+        assert_eq!(state.users.read().unwrap().len(), 2);
+        let world = state.world.read().unwrap();
+        assert_eq!(world.get(5, 5).unwrap().id, TILE_ROBOT);
+        // The second robot falls back to the first free tile
+        assert_eq!(world.get(1, 1).unwrap().id, TILE_ROBOT);
+    }
+}
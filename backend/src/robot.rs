@@ -1,10 +1,23 @@
-use crate::asm::OpCode;
+use crate::asm::{ArithOp, Condition, OpCode, Operand, ReadOpCodeError, Register};
+use crate::config::CostConfig;
+use crate::world::{Tile, TileMap, ORIENT_UP, TILE_ROBOT};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
-pub const STEPS_PER_CHARGE_LEVEL: u8 = 5;
+/// Battery charge used up by each charge level programs see
+pub const ENERGY_PER_CHARGE_LEVEL: u8 = 5;
+/// Battery charge of a full battery
+pub const MAX_BATTERY: u16 = ENERGY_PER_CHARGE_LEVEL as u16 * u8::MAX as u16;
 pub const BIOS_MEM_SIZE: usize = 256;
 pub const DATA_MEM_SIZE: usize = 256;
 pub const PROG_MEM_SIZE: usize = 256;
 pub const CALL_STACK_LEN: usize = 16;
+/// Set in `%flg` when a result was zero
+pub const FLAG_ZERO: u8 = 1;
+/// Set in `%flg` when a result carried out of, or borrowed into, the top bit
+pub const FLAG_CARRY: u8 = 2;
+/// Set in `%flg` when the top bit of a result was set
+pub const FLAG_NEGATIVE: u8 = 4;
 
 #[derive(Clone)]
 pub struct Robot {
@@ -12,28 +25,33 @@ pub struct Robot {
     reg: Registers,
     /// Output register
     ret: u8,
+    /// Flags register, made up of the FLAG_* bits
+    flags: u8,
     /// BIOS memory
     pub bios: [u8; BIOS_MEM_SIZE],
+    /// How many bytes at the start of BIOS memory the assembler emitted
+    bios_len: usize,
     /// BIOS call stack
     bios_call_stack: [u8; CALL_STACK_LEN],
     /// Current position in the BIOS call stack
     bios_call_stack_pos: u8,
     /// General-use memory
     pub memory: [u8; DATA_MEM_SIZE],
+    /// Top of the data stack, which grows down from the end of data memory
+    data_sp: usize,
     /// Program memory
     progmem: [u8; PROG_MEM_SIZE],
+    /// How many bytes at the start of program memory the assembler emitted
+    progmem_len: usize,
     /// Program call stack
     prog_call_stack: [u8; CALL_STACK_LEN],
     /// Current position in the Program call stack
     prog_call_stack_pos: u8,
-    /// Components
-    // TODO: put a component here
-    components: Vec<()>,
     /// Inventory
     inventory: Vec<Item>,
     /// Battery charge
     ///
-    /// Gets divided by STEPS_PER_CHARGE_LEVEL before returning
+    /// Gets divided by ENERGY_PER_CHARGE_LEVEL before returning
     battery: u16,
     /// Current instruction being executed in the bios
     sp: usize,
@@ -43,12 +61,488 @@ pub struct Robot {
     x: usize,
     /// Y position
     y: usize,
+    /// Direction the robot is facing
+    orient: u8,
+    /// Whether execution is in program memory rather than the BIOS
+    in_program: bool,
+    /// How many bytes at the start of data memory belong to the BIOS
+    ///
+    /// Programs get the rest for their own declarations.
+    bios_data_len: usize,
+    /// How many bytes of data memory after the BIOS's the program declared
+    ///
+    /// The data stack can grow down as far as the end of the declarations.
+    program_data_len: usize,
+    /// Cycles left before the last instruction finishes
+    stall: u32,
+    /// BIOS address to jump to on a fault, set by `trap`
+    ///
+    /// It's cleared whenever it's used, so a fault in the handler stops the robot instead of
+    /// looping.
+    fault_handler: Option<u8>,
+    /// Where the last fault happened
+    fault_address: Option<CodeAddress>,
+    /// Whether the robot has stopped executing
+    halted: bool,
 }
 
 impl Robot {
-    fn step(&mut self) -> () {
-        // Read an instruction
-        let next_op = OpCode::read_from(&self.bios[usize::from(self.sp)..]);
+    /// Runs a single cycle, which executes an instruction from the BIOS or program memory unless
+    /// the last one is still going
+    pub fn step(&mut self, costs: &CostConfig) -> StepOutcome {
+        if self.halted {
+            return StepOutcome::Halted;
+        }
+        if self.stall > 0 {
+            self.stall -= 1;
+            return StepOutcome::Continued;
+        }
+        let address = CodeAddress {
+            in_program: self.in_program,
+            address: *self.pointer(),
+        };
+        match self.execute(costs) {
+            StepOutcome::Faulted(fault) => self.handle_fault(fault, address),
+            outcome => outcome,
+        }
+    }
+
+    /// Executes the instruction at the current pointer, without handling faults
+    fn execute(&mut self, costs: &CostConfig) -> StepOutcome {
+        // Read an instruction, so running past the end of the code faults instead of running the
+        // zeroes after it
+        let memory = if self.in_program {
+            self.program_code().get(self.psp..)
+        } else {
+            self.bios_code().get(self.sp..)
+        };
+        let (op, len) = match OpCode::read_from(memory.unwrap_or(&[])) {
+            Ok(op) => op,
+            Err(err) => return StepOutcome::Faulted(err.into()),
+        };
+        let cost = costs.cost(&op);
+        match self.battery.checked_sub(cost.energy) {
+            Some(battery) => self.battery = battery,
+            None => return StepOutcome::Faulted(Fault::BatteryEmpty),
+        }
+        self.stall = cost.cycles.saturating_sub(1);
+        // Point at the next instruction before executing so jumps can overwrite it
+        *self.pointer() += usize::from(len);
+        use OpCode::*;
+        match op.expand_shorthand() {
+            Arithmetic { op, lhs, rhs } => {
+                let rhs = match rhs {
+                    Operand::Register(register) => self.read_reg(register),
+                    Operand::Immediate(value) => value,
+                };
+                if let Err(fault) = self.arithmetic(op, self.read_reg(lhs), rhs) {
+                    return StepOutcome::Faulted(fault);
+                }
+            }
+            Add | Sub | Mul | Div | Mod | And | Or | Xor | Shl | Shr | Cmp => {
+                unreachable!("shorthand arithmetic was expanded")
+            }
+            Not => {
+                let result = !self.reg.rga;
+                self.set_flags(result, false);
+                self.ret = result;
+            }
+            Trap { label } => {
+                if self.in_program {
+                    return StepOutcome::Faulted(Fault::BiosOnly);
+                }
+                self.fault_handler = Some(label);
+            }
+            Jmp { label } => *self.pointer() = usize::from(label),
+            JmpCondition { label } => {
+                if self.ret != 0 {
+                    *self.pointer() = usize::from(label);
+                }
+            }
+            JmpIf { condition, label } => {
+                if self.condition_holds(condition) {
+                    *self.pointer() = usize::from(label);
+                }
+            }
+            MovReg { to, from } => self.write_reg(to, self.read_reg(from)),
+            MovImm { to, from } => self.write_reg(to, from),
+            MovAddr { to, from } => self.write_reg(to, self.memory[usize::from(from)]),
+            StackGet { to } => {
+                if self.data_sp >= DATA_MEM_SIZE {
+                    return StepOutcome::Faulted(Fault::DataStackUnderflow);
+                }
+                self.write_reg(to, self.memory[self.data_sp]);
+                self.data_sp += 1;
+            }
+            StackSet { from } => {
+                // Pushing any further would overwrite declarations
+                if self.data_sp <= self.bios_data_len + self.program_data_len {
+                    return StepOutcome::Faulted(Fault::DataStackOverflow);
+                }
+                self.data_sp -= 1;
+                self.memory[self.data_sp] = self.read_reg(from);
+            }
+            Load { to } => self.write_reg(to, self.memory[usize::from(self.reg.mem)]),
+            Store { from } => self.memory[usize::from(self.reg.mem)] = self.read_reg(from),
+            Exec => {
+                if self.in_program {
+                    return StepOutcome::Faulted(Fault::BiosOnly);
+                }
+                // Remember where to come back to once the program returns
+                let pushed = push_call(
+                    &mut self.bios_call_stack,
+                    &mut self.bios_call_stack_pos,
+                    self.sp,
+                );
+                if let Err(fault) = pushed {
+                    return StepOutcome::Faulted(fault);
+                }
+                self.in_program = true;
+                self.psp = 0;
+            }
+            Return => {
+                if self.in_program && self.prog_call_stack_pos == 0 {
+                    // The program is done, so go back to the BIOS
+                    self.in_program = false;
+                } else if !self.in_program && self.bios_call_stack_pos == 0 {
+                    // The BIOS is done, so the robot has finished
+                    self.halted = true;
+                    return StepOutcome::Halted;
+                }
+                let (stack, pos) = self.call_stack();
+                match pop_call(stack, pos) {
+                    Some(address) => *self.pointer() = address,
+                    None => return StepOutcome::Faulted(Fault::CallStackUnderflow),
+                }
+            }
+            Call { label } => {
+                let address = *self.pointer();
+                let (stack, pos) = self.call_stack();
+                if let Err(fault) = push_call(stack, pos, address) {
+                    return StepOutcome::Faulted(fault);
+                }
+                *self.pointer() = usize::from(label);
+            }
+            CmpCallAddr { component } => {
+                let component = self.memory[usize::from(component)];
+                self.ret = self.call_component(component);
+            }
+            CmpCallImm { component } => self.ret = self.call_component(component),
+            Forward => return StepOutcome::Effect(Effect::Forward),
+            Rotate => return StepOutcome::Effect(Effect::Rotate),
+            Break => return StepOutcome::Effect(Effect::Break),
+            Battery => self.ret = self.charge_level(),
+            InventoryGet => self.ret = self.inventory.len().min(usize::from(u8::MAX)) as u8,
+            InventoryDrop => {
+                let index = usize::from(self.reg.rga);
+                if index < self.inventory.len() {
+                    self.inventory.remove(index);
+                }
+            }
+            InventoryItem => {
+                self.ret = self
+                    .inventory
+                    .get(usize::from(self.reg.rga))
+                    .map(|item| item.id)
+                    .unwrap_or(0)
+            }
+            Output => return StepOutcome::Effect(Effect::Output(self.ret)),
+            Noop => {}
+        }
+        StepOutcome::Continued
+    }
+
+    /// Replaces the BIOS and data memory and starts executing from the beginning
+    ///
+    /// `bios_len` is how many bytes of `bios` are code and `data_len` is how many bytes at the
+    /// start of `memory` the BIOS declared. Any program is unloaded, since its declarations are
+    /// wiped along with the rest of data memory, so `exec` faults until it's uploaded again.
+    pub fn load_bios(
+        &mut self,
+        bios: [u8; BIOS_MEM_SIZE],
+        bios_len: usize,
+        memory: [u8; DATA_MEM_SIZE],
+        data_len: usize,
+    ) {
+        self.bios = bios;
+        self.bios_len = bios_len.min(BIOS_MEM_SIZE);
+        self.memory = memory;
+        self.bios_data_len = data_len.min(DATA_MEM_SIZE);
+        self.progmem_len = 0;
+        self.program_data_len = 0;
+        self.reset();
+    }
+
+    /// Replaces program memory and the program's part of data memory, then starts executing from
+    /// the beginning of the BIOS
+    ///
+    /// `progmem_len` is how many bytes of `progmem` are code and `data_len` is how many bytes of
+    /// `memory` after the BIOS's declarations the program declared.
+    pub fn load_program(
+        &mut self,
+        progmem: [u8; PROG_MEM_SIZE],
+        progmem_len: usize,
+        memory: [u8; DATA_MEM_SIZE],
+        data_len: usize,
+    ) {
+        let start = self.bios_data_len;
+        self.progmem = progmem;
+        self.progmem_len = progmem_len.min(PROG_MEM_SIZE);
+        self.program_data_len = data_len.min(DATA_MEM_SIZE - start);
+        self.memory[start..].copy_from_slice(&memory[start..]);
+        self.reset();
+    }
+
+    /// Where the program's declarations start in data memory
+    pub fn bios_data_len(&self) -> usize {
+        self.bios_data_len
+    }
+
+    /// Where the last fault happened, if there has been one since the robot was reset
+    pub fn fault_address(&self) -> Option<CodeAddress> {
+        self.fault_address
+    }
+
+    /// Program memory
+    pub fn progmem(&self) -> &[u8; PROG_MEM_SIZE] {
+        &self.progmem
+    }
+
+    /// The part of BIOS memory that holds code
+    pub fn bios_code(&self) -> &[u8] {
+        &self.bios[..self.bios_len]
+    }
+
+    /// The part of program memory that holds code
+    pub fn program_code(&self) -> &[u8] {
+        &self.progmem[..self.progmem_len]
+    }
+
+    /// Clears all execution state so the robot starts over from the beginning of its BIOS
+    pub fn reset(&mut self) {
+        self.reg = Registers::default();
+        self.ret = 0;
+        self.flags = 0;
+        self.bios_call_stack = [0; CALL_STACK_LEN];
+        self.bios_call_stack_pos = 0;
+        self.prog_call_stack = [0; CALL_STACK_LEN];
+        self.prog_call_stack_pos = 0;
+        self.sp = 0;
+        self.psp = 0;
+        self.data_sp = DATA_MEM_SIZE;
+        self.in_program = false;
+        self.stall = 0;
+        self.fault_handler = None;
+        self.fault_address = None;
+        self.halted = false;
+    }
+
+    /// Puts the robot back where it was, or on the first free tile if that is taken
+    ///
+    /// Returns false if the world is full
+    pub fn place(&mut self, world: &mut TileMap) -> bool {
+        if world.is_air(self.x, self.y) {
+            world.set_tile(
+                self.x,
+                self.y,
+                Tile {
+                    id: TILE_ROBOT,
+                    orient: self.orient,
+                },
+            );
+            true
+        } else {
+            self.spawn(world)
+        }
+    }
+
+    /// Puts the robot on the first free tile in the world
+    ///
+    /// Returns false if the world is full
+    pub fn spawn(&mut self, world: &mut TileMap) -> bool {
+        match world.find_air() {
+            Some((x, y)) => {
+                self.x = x;
+                self.y = y;
+                world.set_tile(
+                    x,
+                    y,
+                    Tile {
+                        id: TILE_ROBOT,
+                        orient: self.orient,
+                    },
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the robot one tile in the direction it is facing
+    ///
+    /// Sets `%ret` to 1 if the robot moved and 0 if something was in the way
+    pub fn forward(&mut self, world: &mut TileMap) {
+        self.ret = 0;
+        if let Some((x, y)) = world.neighbour(self.x, self.y, self.orient) {
+            if world.translate_tile(self.x, self.y, x, y) {
+                self.x = x;
+                self.y = y;
+                self.ret = 1;
+            }
+        }
+    }
+
+    /// Turns the robot clockwise by a quarter turn
+    pub fn rotate(&mut self, world: &mut TileMap) {
+        self.orient = (self.orient + 1) % 4;
+        world.rotate_tile(self.x, self.y, self.orient);
+    }
+
+    /// Runs an arithmetic instruction, setting the flags from the result and whether it carried
+    ///
+    /// The result goes in `%ret`, except for `cmp` which only sets the flags.
+    fn arithmetic(&mut self, op: ArithOp, a: u8, b: u8) -> Result<(), Fault> {
+        let (result, carry) = match op {
+            ArithOp::Add => a.overflowing_add(b),
+            ArithOp::Sub | ArithOp::Cmp => a.overflowing_sub(b),
+            ArithOp::Mul => a.overflowing_mul(b),
+            ArithOp::Div => (a.checked_div(b).ok_or(Fault::DivideByZero)?, false),
+            ArithOp::Mod => (a.checked_rem(b).ok_or(Fault::DivideByZero)?, false),
+            ArithOp::And => (a & b, false),
+            ArithOp::Or => (a | b, false),
+            ArithOp::Xor => (a ^ b, false),
+            // Shifts carry if any set bits were shifted out
+            ArithOp::Shl => {
+                let shifted = u16::from(a) << b.min(8);
+                (shifted as u8, shifted > u16::from(u8::MAX))
+            }
+            ArithOp::Shr => {
+                let shift = b.min(8);
+                let lost = u16::from(a) & ((1 << shift) - 1);
+                ((u16::from(a) >> shift) as u8, lost != 0)
+            }
+        };
+        self.set_flags(result, carry);
+        if op != ArithOp::Cmp {
+            self.ret = result;
+        }
+        Ok(())
+    }
+
+    fn set_flags(&mut self, result: u8, carry: bool) {
+        self.flags = 0;
+        if result == 0 {
+            self.flags |= FLAG_ZERO;
+        }
+        if carry {
+            self.flags |= FLAG_CARRY;
+        }
+        if result & 0x80 != 0 {
+            self.flags |= FLAG_NEGATIVE;
+        }
+    }
+
+    fn condition_holds(&self, condition: Condition) -> bool {
+        use Condition::*;
+        let flag = |flag: u8| self.flags & flag != 0;
+        match condition {
+            Zero => flag(FLAG_ZERO),
+            NotZero => !flag(FLAG_ZERO),
+            Carry => flag(FLAG_CARRY),
+            NotCarry => !flag(FLAG_CARRY),
+            Negative => flag(FLAG_NEGATIVE),
+            NotNegative => !flag(FLAG_NEGATIVE),
+        }
+    }
+
+    /// The instruction pointer of whichever memory is executing
+    fn pointer(&mut self) -> &mut usize {
+        if self.in_program {
+            &mut self.psp
+        } else {
+            &mut self.sp
+        }
+    }
+
+    /// The call stack of whichever memory is executing, along with its position
+    fn call_stack(&mut self) -> (&mut [u8; CALL_STACK_LEN], &mut u8) {
+        if self.in_program {
+            (&mut self.prog_call_stack, &mut self.prog_call_stack_pos)
+        } else {
+            (&mut self.bios_call_stack, &mut self.bios_call_stack_pos)
+        }
+    }
+
+    /// Records a fault at `address` and jumps to the fault handler with the fault's code in
+    /// `%ret`, or stops the robot if there's no handler
+    fn handle_fault(&mut self, fault: Fault, address: CodeAddress) -> StepOutcome {
+        self.fault_address = Some(address);
+        match self.fault_handler {
+            // There's no charge left to run the handler with
+            Some(handler) if fault != Fault::BatteryEmpty => {
+                self.fault_handler = None;
+                // The handler runs in the BIOS, which can exec the program again if it wants, so
+                // drop the program's calls along with the exec that started it
+                if self.in_program {
+                    self.in_program = false;
+                    self.prog_call_stack_pos = 0;
+                    pop_call(&self.bios_call_stack, &mut self.bios_call_stack_pos);
+                }
+                // The faulting instruction never finished, so it doesn't take any more cycles
+                self.stall = 0;
+                self.sp = usize::from(handler);
+                self.ret = fault.code();
+                StepOutcome::Trapped(fault)
+            }
+            _ => {
+                self.halted = true;
+                StepOutcome::Faulted(fault)
+            }
+        }
+    }
+
+    /// Adds `energy` to the battery, up to a full charge
+    ///
+    /// A robot that ran out stays stopped until its code is uploaded again.
+    pub fn recharge(&mut self, energy: u16) {
+        self.battery = self.battery.saturating_add(energy).min(MAX_BATTERY);
+    }
+
+    /// Returns the battery charge as seen by programs
+    fn charge_level(&self) -> u8 {
+        (self.battery / u16::from(ENERGY_PER_CHARGE_LEVEL)).min(u16::from(u8::MAX)) as u8
+    }
+
+    /// Calls a component and returns its result
+    fn call_component(&mut self, _component: u8) -> u8 {
+        // TODO: call into a component once there are some
+        0
+    }
+
+    fn read_reg(&self, register: Register) -> u8 {
+        use Register::*;
+        match register {
+            RGA => self.reg.rga,
+            RGB => self.reg.rgb,
+            RGC => self.reg.rgc,
+            RGD => self.reg.rgd,
+            RET => self.ret,
+            MEM => self.reg.mem,
+            FLG => self.flags,
+        }
+    }
+
+    fn write_reg(&mut self, register: Register, value: u8) {
+        use Register::*;
+        match register {
+            RGA => self.reg.rga = value,
+            RGB => self.reg.rgb = value,
+            RGC => self.reg.rgc = value,
+            RGD => self.reg.rgd = value,
+            RET => self.ret = value,
+            MEM => self.reg.mem = value,
+            FLG => self.flags = value,
+        }
     }
 }
 
@@ -57,24 +551,122 @@ impl Default for Robot {
         Self {
             reg: Registers::default(),
             ret: 0,
+            flags: 0,
             bios: [0; BIOS_MEM_SIZE],
+            bios_len: 0,
             bios_call_stack: [0; CALL_STACK_LEN],
             bios_call_stack_pos: 0,
             memory: [0; DATA_MEM_SIZE],
+            data_sp: DATA_MEM_SIZE,
             progmem: [0; PROG_MEM_SIZE],
+            progmem_len: 0,
             prog_call_stack: [0; CALL_STACK_LEN],
             prog_call_stack_pos: 0,
-            components: vec![],
             inventory: vec![],
-            battery: u16::from(STEPS_PER_CHARGE_LEVEL) * u16::from(u8::MAX),
+            battery: MAX_BATTERY,
             sp: 0,
             psp: 0,
             x: 0,
             y: 0,
+            orient: ORIENT_UP,
+            in_program: false,
+            bios_data_len: 0,
+            program_data_len: 0,
+            stall: 0,
+            fault_handler: None,
+            fault_address: None,
+            // There's nothing to run until a BIOS is uploaded
+            halted: true,
+        }
+    }
+}
+
+/// The parts of a robot that are kept across server restarts
+#[derive(Deserialize, Serialize)]
+pub struct RobotSnapshot {
+    /// Just the BIOS's code, without the unused memory after it
+    bios: Vec<u8>,
+    memory: Vec<u8>,
+    /// Just the program's code, without the unused memory after it
+    progmem: Vec<u8>,
+    #[serde(default)]
+    bios_data_len: usize,
+    #[serde(default)]
+    program_data_len: usize,
+    inventory: Vec<u8>,
+    battery: u16,
+    x: usize,
+    y: usize,
+    orient: u8,
+}
+
+impl From<&Robot> for RobotSnapshot {
+    fn from(robot: &Robot) -> Self {
+        Self {
+            bios: robot.bios_code().to_vec(),
+            memory: robot.memory.to_vec(),
+            progmem: robot.program_code().to_vec(),
+            bios_data_len: robot.bios_data_len,
+            program_data_len: robot.program_data_len,
+            inventory: robot.inventory.iter().map(|item| item.id).collect(),
+            battery: robot.battery,
+            x: robot.x,
+            y: robot.y,
+            orient: robot.orient,
         }
     }
 }
 
+impl From<RobotSnapshot> for Robot {
+    fn from(snapshot: RobotSnapshot) -> Self {
+        // Copies as much of a saved memory as fits, returning how much that was
+        fn restore(memory: &mut [u8], saved: &[u8]) -> usize {
+            let len = memory.len().min(saved.len());
+            memory[..len].copy_from_slice(&saved[..len]);
+            len
+        }
+        let mut robot = Robot::default();
+        robot.bios_len = restore(&mut robot.bios, &snapshot.bios);
+        restore(&mut robot.memory, &snapshot.memory);
+        robot.progmem_len = restore(&mut robot.progmem, &snapshot.progmem);
+        robot.bios_data_len = snapshot.bios_data_len.min(DATA_MEM_SIZE);
+        robot.program_data_len = snapshot
+            .program_data_len
+            .min(DATA_MEM_SIZE - robot.bios_data_len);
+        robot.inventory = snapshot
+            .inventory
+            .into_iter()
+            .map(|id| Item { id })
+            .collect();
+        robot.battery = snapshot.battery;
+        robot.x = snapshot.x;
+        robot.y = snapshot.y;
+        robot.orient = snapshot.orient % 4;
+        if robot.bios_len > 0 {
+            robot.reset();
+        }
+        robot
+    }
+}
+
+/// Pushes the address to return to onto a call stack
+fn push_call(stack: &mut [u8; CALL_STACK_LEN], pos: &mut u8, address: usize) -> Result<(), Fault> {
+    // Returning past the end of memory would fault anyway
+    let address = u8::try_from(address).map_err(|_| Fault::OutOfBounds)?;
+    let slot = stack
+        .get_mut(usize::from(*pos))
+        .ok_or(Fault::CallStackOverflow)?;
+    *slot = address;
+    *pos += 1;
+    Ok(())
+}
+
+/// Pops the address to return to off a call stack, if there is one
+fn pop_call(stack: &[u8; CALL_STACK_LEN], pos: &mut u8) -> Option<usize> {
+    *pos = pos.checked_sub(1)?;
+    Some(usize::from(stack[usize::from(*pos)]))
+}
+
 /// Input registers
 #[derive(Clone, Copy, Default)]
 pub struct Registers {
@@ -89,3 +681,545 @@ pub struct Registers {
 struct Item {
     id: u8,
 }
+
+/// What happened when a robot executed an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// The instruction was executed and the robot can keep going
+    Continued,
+    /// The instruction was executed but needs the world to act on it
+    Effect(Effect),
+    /// The BIOS returned with nothing to return to, or the robot had already stopped
+    Halted,
+    /// The instruction couldn't be executed and the robot has stopped
+    Faulted(Fault),
+    /// The instruction couldn't be executed and the BIOS fault handler has taken over
+    Trapped(Fault),
+}
+
+/// The location of an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodeAddress {
+    /// Whether the instruction is in program memory rather than the BIOS
+    pub in_program: bool,
+    pub address: usize,
+}
+
+/// Instructions that reach outside of the robot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Move one tile in the direction the robot is facing
+    Forward,
+    /// Turn to face a different direction
+    Rotate,
+    /// Break the tile in front of the robot
+    Break,
+    /// Send a value to the player
+    Output(u8),
+}
+
+/// Reasons a robot can stop executing
+///
+/// The discriminant is the code a fault handler gets in `%ret`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum Fault {
+    /// The instruction pointer ran off the end of memory
+    OutOfBounds = 1,
+    /// The byte at the instruction pointer isn't an instruction
+    InvalidOpcode = 2,
+    /// An instruction names a register that doesn't exist
+    InvalidRegister = 3,
+    /// `div` or `mod` was run with a divisor of zero
+    DivideByZero = 4,
+    /// More calls were nested than fit in the call stack
+    CallStackOverflow = 5,
+    /// A program returned to the BIOS without having been started by `exec`
+    CallStackUnderflow = 6,
+    /// `stac_s` was run when the stack had already grown down to the declared data
+    DataStackOverflow = 7,
+    /// `stac_g` was run with nothing on the stack
+    DataStackUnderflow = 8,
+    /// `exec` or `trap` was run from program memory, when only the BIOS can use them
+    BiosOnly = 9,
+    /// There isn't enough charge left to run the instruction
+    BatteryEmpty = 10,
+}
+
+impl Fault {
+    /// The number given to fault handlers
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<ReadOpCodeError> for Fault {
+    fn from(err: ReadOpCodeError) -> Self {
+        match err {
+            ReadOpCodeError::OutOfBounds => Fault::OutOfBounds,
+            ReadOpCodeError::InvalidOpcode => Fault::InvalidOpcode,
+            ReadOpCodeError::InvalidRegister => Fault::InvalidRegister,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_ok;
+    use crate::config::Cost;
+    use crate::world::{ORIENT_LEFT, ORIENT_RIGHT, TILE_AIR};
+
+    /// Loads code without any declarations into the BIOS
+    fn load_bios(robot: &mut Robot, code: &str) {
+        let (bios, len, _) = assemble_ok(code, 0);
+        robot.load_bios(bios, len, [0; DATA_MEM_SIZE], 0);
+    }
+
+    /// Loads code without any declarations into program memory
+    fn load_program(robot: &mut Robot, code: &str) {
+        let (progmem, len, _) = assemble_ok(code, 0);
+        robot.load_program(progmem, len, [0; DATA_MEM_SIZE], 0);
+    }
+
+    /// Steps the robot until it stops doing anything but continue, giving every effect on the way
+    fn run(robot: &mut Robot) -> (Vec<Effect>, StepOutcome) {
+        let mut effects = Vec::new();
+        loop {
+            match robot.step(&CostConfig::default()) {
+                StepOutcome::Continued => {}
+                StepOutcome::Effect(effect) => effects.push(effect),
+                outcome => return (effects, outcome),
+            }
+        }
+    }
+
+    #[test]
+    fn exec_runs_the_program_and_returns_to_the_bios() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "exec\nout\nexec\nout\nreturn");
+        load_program(&mut robot, "mov %ret 7\nout\nreturn");
+        let (effects, outcome) = run(&mut robot);
+        assert_eq!(effects, vec![Effect::Output(7); 4]);
+        // Returning from the top of the BIOS finishes cleanly
+        assert_eq!(outcome, StepOutcome::Halted);
+        assert_eq!(robot.fault_address(), None);
+        assert_eq!(robot.step(&CostConfig::default()), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn new_robots_wait_for_a_bios() {
+        let mut robot = Robot::default();
+        let battery = robot.battery;
+        assert_eq!(robot.step(&CostConfig::default()), StepOutcome::Halted);
+        assert_eq!(robot.battery, battery);
+        load_bios(&mut robot, "out\nreturn");
+        assert_eq!(run(&mut robot).0, vec![Effect::Output(0)]);
+    }
+
+    #[test]
+    fn running_past_the_code_faults() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "mov %rga 1");
+        let battery = robot.battery;
+        assert_eq!(run(&mut robot).1, StepOutcome::Faulted(Fault::OutOfBounds));
+        assert_eq!(robot.battery, battery - 1);
+
+        // Nothing has been uploaded to program memory yet
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "exec");
+        let battery = robot.battery;
+        assert_eq!(run(&mut robot).1, StepOutcome::Faulted(Fault::OutOfBounds));
+        let address = CodeAddress {
+            in_program: true,
+            address: 0,
+        };
+        assert_eq!(robot.fault_address(), Some(address));
+        assert_eq!(robot.battery, battery - 1);
+    }
+
+    #[test]
+    fn moves_and_jumps() {
+        let mut robot = Robot::default();
+        let code = "mov %rgb 3\nmov %rga %rgb\njmp $skip\nout\n\
+                    skip:\nmov %ret %rga\nout\n\
+                    mov %ret 0\njmpc $end\nout\nmov %ret 1\njmpc $end\nout\n\
+                    end:\nreturn";
+        load_bios(&mut robot, code);
+        let (effects, outcome) = run(&mut robot);
+        // `jmpc` only jumps when %ret isn't 0
+        assert_eq!(effects, vec![Effect::Output(3), Effect::Output(0)]);
+        assert_eq!(outcome, StepOutcome::Halted);
+    }
+
+    #[test]
+    fn world_instructions_are_effects() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "fwd\nrot\nbrk\nreturn");
+        let (effects, _) = run(&mut robot);
+        assert_eq!(
+            effects,
+            vec![Effect::Forward, Effect::Rotate, Effect::Break]
+        );
+    }
+
+    #[test]
+    fn invalid_instructions_fault_where_they_are() {
+        let mut robot = Robot::default();
+        let mut bios = [0; BIOS_MEM_SIZE];
+        bios[1] = 63;
+        robot.load_bios(bios, 2, [0; DATA_MEM_SIZE], 0);
+        assert_eq!(
+            run(&mut robot).1,
+            StepOutcome::Faulted(Fault::InvalidOpcode)
+        );
+        let address = CodeAddress {
+            in_program: false,
+            address: 1,
+        };
+        assert_eq!(robot.fault_address(), Some(address));
+        assert_eq!(robot.step(&CostConfig::default()), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn instructions_take_their_cycles_and_energy() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "fwd\nnoop\nreturn");
+        let costs = CostConfig::default();
+        let battery = robot.battery;
+        assert_eq!(robot.step(&costs), StepOutcome::Effect(Effect::Forward));
+        let forward = costs.cost(&OpCode::<u8>::Forward);
+        for _ in 1..forward.cycles {
+            assert_eq!(robot.step(&costs), StepOutcome::Continued);
+        }
+        assert_eq!(robot.battery, battery - forward.energy);
+        assert_eq!(robot.step(&costs), StepOutcome::Continued);
+        assert_eq!(robot.battery, battery - forward.energy - 1);
+    }
+
+    #[test]
+    fn running_out_of_battery_stops_the_robot() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "top:\nfwd\njmp $top");
+        let (effects, outcome) = run(&mut robot);
+        assert_eq!(outcome, StepOutcome::Faulted(Fault::BatteryEmpty));
+        assert!(!effects.is_empty());
+        assert_eq!(robot.step(&CostConfig::default()), StepOutcome::Halted);
+
+        // Charging back up doesn't restart the robot, but uploading again does
+        robot.recharge(30);
+        assert_eq!(robot.step(&CostConfig::default()), StepOutcome::Halted);
+        load_bios(&mut robot, "top:\nfwd\njmp $top");
+        assert_eq!(
+            robot.step(&CostConfig::default()),
+            StepOutcome::Effect(Effect::Forward)
+        );
+        robot.recharge(u16::MAX);
+        assert_eq!(robot.battery, MAX_BATTERY);
+    }
+
+    #[test]
+    fn call_returns_to_the_next_instruction() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "exec\nout\nreturn");
+        let program = "call $f\nmov %ret 2\ncall $f\nreturn\nf:\nout\nreturn";
+        load_program(&mut robot, program);
+        let (effects, _) = run(&mut robot);
+        let expected = vec![Effect::Output(0), Effect::Output(2), Effect::Output(2)];
+        assert_eq!(effects, expected);
+    }
+
+    #[test]
+    fn deep_calls_overflow_the_call_stack() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "f:\ncall $f");
+        assert_eq!(
+            run(&mut robot).1,
+            StepOutcome::Faulted(Fault::CallStackOverflow)
+        );
+        assert_eq!(usize::from(robot.bios_call_stack_pos), CALL_STACK_LEN);
+    }
+
+    #[test]
+    fn arithmetic_sets_the_flags() {
+        let mut robot = Robot::default();
+        let code = "mov %rga 200\nmov %rgb 100\nadd\nmov %ret %flg\nout\n\
+                    mov %rga 1\nmov %rgb 2\nsub\nmov %ret %flg\nout\n\
+                    mov %rga 5\nmov %rgb 5\ncmp\nmov %ret %flg\nout\nreturn";
+        load_bios(&mut robot, code);
+        let (effects, _) = run(&mut robot);
+        let expected = vec![
+            Effect::Output(FLAG_CARRY),
+            Effect::Output(FLAG_CARRY | FLAG_NEGATIVE),
+            Effect::Output(FLAG_ZERO),
+        ];
+        assert_eq!(effects, expected);
+    }
+
+    #[test]
+    fn arithmetic_with_operands() {
+        let mut robot = Robot::default();
+        let code = "mov %rgc 7\nmov %rgd 3\nsub %rgc %rgd\nout\nmul %rgc 2\nout\n\
+                    cmp %rgd 3\nmov %ret %flg\nout\nreturn";
+        load_bios(&mut robot, code);
+        let (effects, _) = run(&mut robot);
+        let expected = vec![
+            Effect::Output(4),
+            Effect::Output(14),
+            Effect::Output(FLAG_ZERO),
+        ];
+        assert_eq!(effects, expected);
+    }
+
+    #[test]
+    fn bitwise_instructions() {
+        let mut robot = Robot::default();
+        let code = "mov %rga 12\nmov %rgb 10\nand\nout\nor\nout\nxor\nout\nnot\nout\nmod\nout\n\
+                    mov %rgb 2\nshl\nout\nshr\nout\n\
+                    mov %rga 129\nmov %rgb 1\nshl\nout\nmov %ret %flg\nout\nreturn";
+        load_bios(&mut robot, code);
+        let outputs: Vec<_> = run(&mut robot)
+            .0
+            .into_iter()
+            .map(|effect| match effect {
+                Effect::Output(value) => value,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(outputs, vec![8, 14, 6, 243, 2, 48, 3, 2, FLAG_CARRY]);
+    }
+
+    #[test]
+    fn conditional_jumps_check_the_flags() {
+        let mut robot = Robot::default();
+        let code = "mov %rga 1\nmov %rgb 2\ncmp\njc $less\nreturn\n\
+                    less:\njz $equal\njn $negative\nreturn\n\
+                    negative:\nout\nreturn\n\
+                    equal:\nreturn";
+        load_bios(&mut robot, code);
+        let (effects, _) = run(&mut robot);
+        // 1 - 2 borrows, isn't zero and wraps to 255
+        assert_eq!(effects, vec![Effect::Output(0)]);
+    }
+
+    #[test]
+    fn data_memory_can_be_read_and_written() {
+        let code = "let $x = 9\nmov %mem 3\nmov %rga $x\nstore %rga\nload %ret\nout\nreturn";
+        let (bios, len, memory) = assemble_ok(code, 0);
+        let mut robot = Robot::default();
+        robot.load_bios(bios, len, memory, 1);
+        assert_eq!(run(&mut robot).0, vec![Effect::Output(9)]);
+        assert_eq!(&robot.memory[..4], &[9, 0, 0, 9]);
+    }
+
+    #[test]
+    fn the_data_stack_pops_in_reverse() {
+        let mut robot = Robot::default();
+        let code = "mov %rga 1\nstac_s %rga\nmov %rga 2\nstac_s %rga\n\
+                    stac_g %ret\nout\nstac_g %ret\nout\nstac_g %ret";
+        load_bios(&mut robot, code);
+        let (effects, outcome) = run(&mut robot);
+        assert_eq!(effects, vec![Effect::Output(2), Effect::Output(1)]);
+        assert_eq!(outcome, StepOutcome::Faulted(Fault::DataStackUnderflow));
+        assert_eq!(&robot.memory[DATA_MEM_SIZE - 2..], &[2, 1]);
+    }
+
+    #[test]
+    fn filling_data_memory_overflows_the_data_stack() {
+        let (bios, bios_len, memory) = assemble_ok("let $x = 9\nmov %rga 1\nexec\nreturn", 0);
+        let (progmem, progmem_len, program_memory) =
+            assemble_ok("let $y = 7\nloop:\nstac_s %rga\njmp $loop", 1);
+        let mut robot = Robot::default();
+        robot.load_bios(bios, bios_len, memory, 1);
+        robot.load_program(progmem, progmem_len, program_memory, 1);
+        assert_eq!(
+            run(&mut robot).1,
+            StepOutcome::Faulted(Fault::DataStackOverflow)
+        );
+        // The stack stops short of both declarations
+        assert_eq!(robot.data_sp, 2);
+        assert_eq!(&robot.memory[..3], &[9, 7, 1]);
+    }
+
+    #[test]
+    fn faults_jump_to_the_handler_once() {
+        let mut robot = Robot::default();
+        let code = "trap $handler\nexec\nreturn\nhandler:\nout\nexec";
+        load_bios(&mut robot, code);
+        load_program(&mut robot, "mov %rgb 0\ndiv");
+        let costs = CostConfig::default();
+        let mut outcomes = Vec::new();
+        loop {
+            match robot.step(&costs) {
+                StepOutcome::Continued => {}
+                StepOutcome::Halted => break,
+                outcome => outcomes.push(outcome),
+            }
+        }
+        let expected = vec![
+            StepOutcome::Trapped(Fault::DivideByZero),
+            StepOutcome::Effect(Effect::Output(Fault::DivideByZero.code())),
+            // The handler was used up, so the same fault stops the robot
+            StepOutcome::Faulted(Fault::DivideByZero),
+        ];
+        assert_eq!(outcomes, expected);
+        let address = CodeAddress {
+            in_program: true,
+            address: 3,
+        };
+        assert_eq!(robot.fault_address(), Some(address));
+    }
+
+    #[test]
+    fn trapping_program_faults_does_not_leak_call_stack() {
+        let mut robot = Robot::default();
+        let code = "mov %rgc 40\nloop:\ntrap $handler\nexec\nreturn\n\
+                    handler:\nsub %rgc 1\nmov %rgc %ret\njnz $loop\nreturn";
+        load_bios(&mut robot, code);
+        load_program(&mut robot, "call $f\nf:\nmov %rgb 0\ndiv");
+        let mut costs = CostConfig::default();
+        let slow = Cost {
+            cycles: 5,
+            energy: 1,
+        };
+        costs.instructions.insert("div".into(), slow);
+        let mut trapped = 0;
+        loop {
+            match robot.step(&costs) {
+                StepOutcome::Continued => {}
+                StepOutcome::Trapped(Fault::DivideByZero) => {
+                    // The handler starts straight away
+                    assert_eq!(robot.stall, 0);
+                    trapped += 1;
+                }
+                outcome => {
+                    assert_eq!(outcome, StepOutcome::Halted);
+                    break;
+                }
+            }
+        }
+        assert_eq!(trapped, 40);
+        assert_eq!(robot.bios_call_stack_pos, 0);
+        assert_eq!(robot.prog_call_stack_pos, 0);
+    }
+
+    #[test]
+    fn exec_from_a_program_faults() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "exec");
+        load_program(&mut robot, "exec");
+        assert_eq!(run(&mut robot).1, StepOutcome::Faulted(Fault::BiosOnly));
+    }
+
+    #[test]
+    fn new_bioses_unload_the_program() {
+        let mut robot = Robot::default();
+        load_bios(&mut robot, "exec\nreturn");
+        load_program(&mut robot, "out\nreturn");
+        load_bios(&mut robot, "exec\nreturn");
+        assert!(robot.program_code().is_empty());
+        let (effects, outcome) = run(&mut robot);
+        assert!(effects.is_empty());
+        assert_eq!(outcome, StepOutcome::Faulted(Fault::OutOfBounds));
+    }
+
+    #[test]
+    fn programs_keep_the_bios_data() {
+        let mut robot = Robot::default();
+        robot.load_bios([0; BIOS_MEM_SIZE], 0, [1; DATA_MEM_SIZE], 2);
+        robot.load_program([0; PROG_MEM_SIZE], 0, [2; DATA_MEM_SIZE], 1);
+        assert_eq!(&robot.memory[..4], &[1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn moving_keeps_the_robot_and_its_tile_together() {
+        let mut world = TileMap::new(5, 3);
+        world.generate_box_corner();
+        let mut robot = Robot::default();
+        assert!(robot.spawn(&mut world));
+        assert_eq!((robot.x, robot.y), (1, 1));
+
+        // Facing the wall above
+        robot.forward(&mut world);
+        assert_eq!(robot.ret, 0);
+        assert_eq!((robot.x, robot.y), (1, 1));
+        assert_eq!(world.get(1, 1).unwrap().id, TILE_ROBOT);
+
+        robot.rotate(&mut world);
+        assert_eq!(robot.orient, ORIENT_RIGHT);
+        assert_eq!(world.get(1, 1).unwrap().orient, ORIENT_RIGHT);
+        robot.forward(&mut world);
+        assert_eq!(robot.ret, 1);
+        assert_eq!((robot.x, robot.y), (2, 1));
+        assert_eq!(world.get(1, 1).unwrap().id, TILE_AIR);
+        assert_eq!(
+            world.get(2, 1),
+            Some(&Tile {
+                id: TILE_ROBOT,
+                orient: ORIENT_RIGHT
+            })
+        );
+    }
+
+    #[test]
+    fn robots_block_each_other() {
+        let mut world = TileMap::new(5, 3);
+        world.generate_box_corner();
+        let mut first = Robot::default();
+        let mut second = Robot::default();
+        assert!(first.spawn(&mut world));
+        assert!(second.spawn(&mut world));
+        assert_eq!((second.x, second.y), (2, 1));
+
+        first.rotate(&mut world);
+        first.forward(&mut world);
+        assert_eq!(first.ret, 0);
+        assert_eq!((first.x, first.y), (1, 1));
+
+        // The robot in the way can still go as far as the far wall
+        second.rotate(&mut world);
+        for _ in 0..3 {
+            second.forward(&mut world);
+        }
+        assert_eq!(second.ret, 0);
+        assert_eq!((second.x, second.y), (3, 1));
+        assert_eq!(world.get(3, 1).unwrap().id, TILE_ROBOT);
+        assert_eq!(world.get(2, 1).unwrap().id, TILE_AIR);
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut robot = Robot::default();
+        let mut bios = [0; BIOS_MEM_SIZE];
+        bios[..3].copy_from_slice(&[1, 2, 3]);
+        let mut progmem = [0; PROG_MEM_SIZE];
+        progmem[..2].copy_from_slice(&[4, 5]);
+        let mut memory = [0; DATA_MEM_SIZE];
+        memory[..3].copy_from_slice(&[6, 7, 8]);
+        robot.load_bios(bios, 3, memory, 2);
+        robot.load_program(progmem, 2, memory, 1);
+        robot.inventory = vec![Item { id: 9 }, Item { id: 10 }];
+        robot.battery = 1234;
+        robot.x = 5;
+        robot.y = 6;
+        robot.orient = ORIENT_LEFT;
+
+        let json = serde_json::to_string(&RobotSnapshot::from(&robot)).unwrap();
+        let snapshot: RobotSnapshot = serde_json::from_str(&json).unwrap();
+        let restored = Robot::from(snapshot);
+        assert_eq!(restored.bios_code(), &[1, 2, 3]);
+        assert_eq!(&restored.bios[..], &robot.bios[..]);
+        assert_eq!(restored.program_code(), &[4, 5]);
+        assert_eq!(&restored.progmem[..], &robot.progmem[..]);
+        assert_eq!(&restored.memory[..], &robot.memory[..]);
+        assert_eq!(restored.bios_data_len, 2);
+        assert_eq!(restored.program_data_len, 1);
+        let ids: Vec<_> = restored.inventory.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![9, 10]);
+        assert_eq!(restored.battery, 1234);
+        assert_eq!((restored.x, restored.y), (5, 6));
+        assert_eq!(restored.orient, ORIENT_LEFT);
+        // Robots with a BIOS carry on running it, and ones without keep waiting for one
+        assert!(!restored.halted);
+        assert!(Robot::from(RobotSnapshot::from(&Robot::default())).halted);
+    }
+}
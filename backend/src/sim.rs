@@ -0,0 +1,259 @@
+use crate::api::Response as ApiResponse;
+use crate::config::SimulationConfig;
+use crate::disasm;
+use crate::frame;
+use crate::robot::{Effect, Fault, Robot, StepOutcome};
+use crate::world::TileMap;
+use crate::ServerState;
+use log::{debug, error};
+use std::time::Duration;
+use tokio::time;
+
+/// Steps every robot in the world once per tick, forever
+pub async fn run(state: ServerState, config: SimulationConfig) {
+    let mut interval = time::interval(Duration::from_millis(config.tick_ms));
+    loop {
+        interval.tick().await;
+        tick(&state, &config);
+    }
+}
+
+/// Advances the world by a single tick
+fn tick(state: &ServerState, config: &SimulationConfig) {
+    let mut users = match state.users.write() {
+        Ok(users) => users,
+        Err(err) => {
+            error!("Error getting access to users: {}", err);
+            return;
+        }
+    };
+    let mut world = match state.world.write() {
+        Ok(world) => world,
+        Err(err) => {
+            error!("Error getting access to the world: {}", err);
+            return;
+        }
+    };
+    // Every robot that faulted this tick, by user
+    let mut faults = Vec::new();
+    for (user_id, user) in users.iter_mut() {
+        let robot = &mut user.robot;
+        robot.recharge(config.recharge_per_tick);
+        for _ in 0..config.cycles_per_tick {
+            match robot.step(&config.costs) {
+                StepOutcome::Continued => {}
+                StepOutcome::Effect(effect) => apply_effect(*user_id, robot, &mut world, effect),
+                StepOutcome::Halted => break,
+                StepOutcome::Faulted(fault) => {
+                    debug!("Robot for user {} faulted: {:?}", user_id, fault);
+                    faults.push((*user_id, fault_report(robot, fault, false)));
+                    break;
+                }
+                StepOutcome::Trapped(fault) => {
+                    debug!("Robot for user {} trapped: {:?}", user_id, fault);
+                    faults.push((*user_id, fault_report(robot, fault, true)));
+                }
+            }
+        }
+    }
+    send_map_updates(state, &mut world);
+    send_faults(state, &faults);
+}
+
+/// Describes a fault that just happened for the robot's owner
+fn fault_report(robot: &Robot, fault: Fault, handled: bool) -> ApiResponse {
+    let address = robot
+        .fault_address()
+        .expect("robots record where they faulted");
+    let memory: &[u8] = if address.in_program {
+        robot.progmem()
+    } else {
+        &robot.bios
+    };
+    ApiResponse::RobotFault {
+        fault,
+        handled,
+        in_program: address.in_program,
+        address: address.address,
+        instruction: disasm::disassemble_at(memory, address.address),
+    }
+}
+
+/// Tells the players whose robots faulted what went wrong
+fn send_faults(state: &ServerState, faults: &[(usize, ApiResponse)]) {
+    if faults.is_empty() {
+        return;
+    }
+    let peer_map = match state.peer_map.lock() {
+        Ok(peer_map) => peer_map,
+        Err(err) => {
+            error!("Error getting access to peers: {}", err);
+            return;
+        }
+    };
+    for peer in peer_map.values() {
+        for (_, report) in faults
+            .iter()
+            .filter(|(user_id, _)| *user_id == peer.user_id)
+        {
+            peer.send(report);
+        }
+    }
+}
+
+/// Tells every client about the tiles that changed in their viewport
+fn send_map_updates(state: &ServerState, world: &mut TileMap) {
+    let changes = world.take_changes();
+    if changes.is_empty() {
+        return;
+    }
+    let peer_map = match state.peer_map.lock() {
+        Ok(peer_map) => peer_map,
+        Err(err) => {
+            error!("Error getting access to peers: {}", err);
+            return;
+        }
+    };
+    for peer in peer_map.values() {
+        let viewport = match &peer.viewport {
+            Some(viewport) => viewport,
+            None => continue,
+        };
+        let tiles: Vec<_> = changes
+            .iter()
+            .filter(|&&(x, y)| viewport.contains(x, y))
+            .filter_map(|&(x, y)| world.get(x, y).map(|tile| (x, y, tile.id, tile.orient)))
+            .collect();
+        if !tiles.is_empty() {
+            peer.send_frame(frame::map_update(viewport, &tiles));
+        }
+    }
+}
+
+/// Carries out an instruction that reaches outside of the robot
+fn apply_effect(user_id: usize, robot: &mut Robot, world: &mut TileMap, effect: Effect) {
+    match effect {
+        Effect::Forward => robot.forward(world),
+        Effect::Rotate => robot.rotate(world),
+        // TODO: mine blocks
+        Effect::Break => {}
+        Effect::Output(value) => debug!("Robot for user {} output {}", user_id, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_ok;
+    use crate::config::WorldConfig;
+    use crate::library::Library;
+    use crate::user::User;
+    use crate::world::{TILE_AIR, TILE_ROBOT};
+    use crate::Peer;
+    use cookie::Key;
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+    use tokio_tungstenite::tungstenite::protocol::Message;
+
+    fn new_state() -> ServerState {
+        ServerState::new(
+            Key::from(&[0; 64]),
+            &WorldConfig::default(),
+            Library::default(),
+        )
+    }
+
+    /// Adds a user whose robot is running `code`, which has no declarations, on the first free tile
+    fn add_robot(state: &ServerState, user_id: usize, code: &str) {
+        let (bios, len, memory) = assemble_ok(code, 0);
+        let mut user = User::new();
+        user.robot.load_bios(bios, len, memory, 0);
+        assert!(user.robot.spawn(&mut state.world.write().unwrap()));
+        state.users.write().unwrap().insert(user_id, user);
+    }
+
+    /// Connects a client for the user, giving everything that gets sent to it
+    fn connect(
+        state: &ServerState,
+        port: u16,
+        user_id: usize,
+        viewport: Option<(usize, usize, usize, usize)>,
+    ) -> UnboundedReceiver<Message> {
+        let (tx, rx) = unbounded();
+        let viewport =
+            viewport.map(|(x, y, w, h)| state.world.read().unwrap().viewport(x, y, w, h));
+        let peer = Peer {
+            user_id,
+            tx,
+            viewport,
+        };
+        let addr = ([127, 0, 0, 1], port).into();
+        state.peer_map.lock().unwrap().insert(addr, peer);
+        rx
+    }
+
+    /// Takes everything that has been sent so far
+    fn received(rx: &mut UnboundedReceiver<Message>) -> Vec<Message> {
+        std::iter::from_fn(|| rx.try_next().ok().flatten()).collect()
+    }
+
+    fn tile_id(state: &ServerState, x: usize, y: usize) -> u8 {
+        state.world.read().unwrap().get(x, y).unwrap().id
+    }
+
+    #[test]
+    fn forward_moves_robots_on_the_map() {
+        let state = new_state();
+        let config = SimulationConfig {
+            cycles_per_tick: 100,
+            ..SimulationConfig::default()
+        };
+        add_robot(&state, 0, "rot\nfwd\nreturn");
+        let mut watching = connect(&state, 1, 0, Some((0, 0, 4, 4)));
+        let mut elsewhere = connect(&state, 2, 0, Some((10, 10, 4, 4)));
+        tick(&state, &config);
+        assert_eq!(tile_id(&state, 1, 1), TILE_AIR);
+        assert_eq!(tile_id(&state, 2, 1), TILE_ROBOT);
+        // Only the client looking at the robot hears about it
+        let frames = received(&mut watching);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].is_binary());
+        assert!(received(&mut elsewhere).is_empty());
+    }
+
+    #[test]
+    fn robots_run_cycles_per_tick_cycles() {
+        let state = new_state();
+        let mut config = SimulationConfig {
+            cycles_per_tick: 3,
+            ..SimulationConfig::default()
+        };
+        // Every instruction takes a single cycle
+        config.costs.instructions.clear();
+        add_robot(&state, 0, &("rot\nrot\n".to_string() + &"fwd\n".repeat(10)));
+        tick(&state, &config);
+        assert_eq!(tile_id(&state, 1, 2), TILE_ROBOT);
+        tick(&state, &config);
+        assert_eq!(tile_id(&state, 1, 2), TILE_AIR);
+        assert_eq!(tile_id(&state, 1, 5), TILE_ROBOT);
+    }
+
+    #[test]
+    fn faults_are_only_reported_to_their_owner() {
+        let state = new_state();
+        let config = SimulationConfig::default();
+        add_robot(&state, 0, "mov %rgb 0\ndiv");
+        add_robot(&state, 1, "top:\nnoop\njmp $top");
+        let mut owner = connect(&state, 1, 0, None);
+        let mut other = connect(&state, 2, 1, None);
+        tick(&state, &config);
+        tick(&state, &config);
+        let messages = received(&mut owner);
+        assert_eq!(messages.len(), 1);
+        let report: serde_json::Value =
+            serde_json::from_str(messages[0].to_text().unwrap()).unwrap();
+        assert_eq!(report["t"], "f");
+        assert_eq!(report["d"]["handled"], false);
+        assert_eq!(report["d"]["address"], 3);
+        assert!(received(&mut other).is_empty());
+    }
+}
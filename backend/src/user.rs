@@ -2,7 +2,7 @@ use crate::robot::Robot;
 
 #[derive(Clone)]
 pub struct User {
-    robot: Robot,
+    pub robot: Robot,
 }
 
 impl User {
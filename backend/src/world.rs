@@ -0,0 +1,350 @@
+/// Tile id for empty space
+pub const TILE_AIR: u8 = 0;
+/// Tile id for a robot
+pub const TILE_ROBOT: u8 = 1;
+/// Tile id for the top and left walls
+pub const TILE_WALL: u8 = 2;
+/// Tile id for the bottom and right walls
+pub const TILE_WALL_FAR: u8 = 3;
+
+/// Orientation facing towards y = 0
+pub const ORIENT_UP: u8 = 0;
+/// Orientation facing towards x = w
+pub const ORIENT_RIGHT: u8 = 1;
+/// Orientation facing towards y = h
+pub const ORIENT_DOWN: u8 = 2;
+/// Orientation facing towards x = 0
+pub const ORIENT_LEFT: u8 = 3;
+
+pub struct Viewport {
+    cx: usize,
+    cy: usize,
+    cw: usize,
+    ch: usize,
+}
+
+impl Viewport {
+    pub fn x(&self) -> usize {
+        self.cx
+    }
+
+    pub fn y(&self) -> usize {
+        self.cy
+    }
+
+    pub fn width(&self) -> usize {
+        self.cw
+    }
+
+    pub fn height(&self) -> usize {
+        self.ch
+    }
+
+    /// Checks whether the given world position is inside the view
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.cx && y >= self.cy && x - self.cx < self.cw && y - self.cy < self.ch
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub id: u8,
+    pub orient: u8,
+}
+
+impl Tile {
+    /// An empty tile
+    pub const AIR: Tile = Tile {
+        id: TILE_AIR,
+        orient: 0,
+    };
+}
+
+/// A grid of tiles, stored one row after another
+pub struct TileMap {
+    w: usize,
+    h: usize,
+
+    tile_map: Vec<Tile>,
+    /// Positions that may have changed since the last call to `take_changes`
+    changes: Vec<(usize, usize)>,
+}
+
+impl TileMap {
+    pub fn new(w: usize, h: usize) -> Self {
+        TileMap {
+            w,
+            h,
+            tile_map: vec![Tile::AIR; w * h],
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.w
+    }
+
+    pub fn height(&self) -> usize {
+        self.h
+    }
+
+    /// Gets the offset of (x, y) in the tile storage, if it's inside the map
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.w && y < self.h {
+            Some(y * self.w + x)
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<&Tile> {
+        self.index(x, y).map(move |index| &self.tile_map[index])
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut Tile> {
+        match self.index(x, y) {
+            Some(index) => {
+                self.changes.push((x, y));
+                Some(&mut self.tile_map[index])
+            }
+            None => None,
+        }
+    }
+
+    /// Gets every position that may have changed since the last call, without duplicates
+    pub fn take_changes(&mut self) -> Vec<(usize, usize)> {
+        let mut changes = std::mem::take(&mut self.changes);
+        changes.sort_unstable();
+        changes.dedup();
+        changes
+    }
+
+    pub fn viewport(&self, vx: usize, vy: usize, vw: usize, vh: usize) -> Viewport {
+        Viewport {
+            cx: vx,
+            cy: vy,
+            cw: vw,
+            ch: vh,
+        }
+    }
+
+    /// Gets the (id, orient) pairs for every tile in the view, one column after another
+    ///
+    /// Tiles outside of the map are sent as air
+    pub fn gen_view(&self, view: &Viewport) -> Vec<u8> {
+        let mut ret_val: Vec<u8> = Vec::with_capacity(view.cw * view.ch * 2);
+        for x in 0..view.cw {
+            for y in 0..view.ch {
+                let tile = x
+                    .checked_add(view.cx)
+                    .zip(y.checked_add(view.cy))
+                    .and_then(|(x, y)| self.get(x, y))
+                    .unwrap_or(&Tile::AIR);
+                ret_val.push(tile.id);
+                ret_val.push(tile.orient);
+            }
+        }
+
+        ret_val
+    }
+
+    pub fn generate_box_corner(&mut self) {
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let id = if x == 0 || y == 0 {
+                    TILE_WALL
+                } else if x == self.w - 1 || y == self.h - 1 {
+                    TILE_WALL_FAR
+                } else {
+                    continue;
+                };
+                self.set_tile(x, y, Tile { id, orient: 0 });
+            }
+        }
+    }
+
+    /// Moves the tile at (x, y) to (tx, ty), leaving air behind
+    ///
+    /// Returns false if either position is outside the map or the target isn't air
+    pub fn translate_tile(&mut self, x: usize, y: usize, tx: usize, ty: usize) -> bool {
+        match (self.index(x, y), self.index(tx, ty)) {
+            (Some(from), Some(to)) if self.tile_map[to].id == TILE_AIR => {
+                self.tile_map[to] = self.tile_map[from];
+                self.tile_map[from] = Tile::AIR;
+                self.changes.push((x, y));
+                self.changes.push((tx, ty));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn rotate_tile(&mut self, x: usize, y: usize, orient: u8) {
+        if let Some(tile) = self.get_mut(x, y) {
+            tile.orient = orient;
+        }
+    }
+
+    /// Places a tile at the given position, overwriting whatever was there
+    pub fn set_tile(&mut self, x: usize, y: usize, tile: Tile) {
+        if let Some(old_tile) = self.get_mut(x, y) {
+            *old_tile = tile;
+        }
+    }
+
+    /// Checks whether the given position is inside the map and empty
+    pub fn is_air(&self, x: usize, y: usize) -> bool {
+        self.get(x, y).is_some_and(|tile| tile.id == TILE_AIR)
+    }
+
+    /// Finds the first empty tile, scanning row by row
+    pub fn find_air(&self) -> Option<(usize, usize)> {
+        self.tile_map
+            .iter()
+            .position(|tile| tile.id == TILE_AIR)
+            .map(|index| (index % self.w, index / self.w))
+    }
+
+    /// Gets the position one tile away from (x, y) in the direction of `orient`
+    ///
+    /// Returns None if that would be off the edge of the map
+    pub fn neighbour(&self, x: usize, y: usize, orient: u8) -> Option<(usize, usize)> {
+        let (tx, ty) = match orient {
+            ORIENT_UP => (Some(x), y.checked_sub(1)),
+            ORIENT_RIGHT => (x.checked_add(1), Some(y)),
+            ORIENT_DOWN => (Some(x), y.checked_add(1)),
+            ORIENT_LEFT => (x.checked_sub(1), Some(y)),
+            _ => (None, None),
+        };
+        let (tx, ty) = tx.zip(ty)?;
+        self.index(tx, ty).map(|_| (tx, ty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn robot() -> Tile {
+        Tile {
+            id: TILE_ROBOT,
+            orient: ORIENT_UP,
+        }
+    }
+
+    #[test]
+    fn get_checks_bounds() {
+        let map = TileMap::new(512, 128);
+        assert!(map.get(0, 0).is_some());
+        assert!(map.get(511, 127).is_some());
+        assert!(map.get(512, 0).is_none());
+        assert!(map.get(0, 128).is_none());
+        assert!(map.get(127, 511).is_none());
+    }
+
+    #[test]
+    fn tiles_are_distinct_on_non_square_maps() {
+        for &(w, h) in &[(7, 3), (3, 7), (1, 5), (5, 1)] {
+            let mut map = TileMap::new(w, h);
+            for y in 0..h {
+                for x in 0..w {
+                    map.get_mut(x, y).unwrap().id = (y * w + x) as u8;
+                }
+            }
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(map.get(x, y).unwrap().id, (y * w + x) as u8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_corner_walls_every_edge() {
+        let mut map = TileMap::new(6, 3);
+        map.generate_box_corner();
+        for x in 0..6 {
+            assert_eq!(map.get(x, 0).unwrap().id, TILE_WALL);
+        }
+        for x in 1..6 {
+            assert_eq!(map.get(x, 2).unwrap().id, TILE_WALL_FAR);
+        }
+        assert_eq!(map.get(0, 1).unwrap().id, TILE_WALL);
+        assert_eq!(map.get(5, 1).unwrap().id, TILE_WALL_FAR);
+        for x in 1..5 {
+            assert!(map.is_air(x, 1));
+        }
+    }
+
+    #[test]
+    fn view_is_column_major_and_padded_with_air() {
+        let mut map = TileMap::new(4, 2);
+        map.set_tile(3, 1, robot());
+        let view = map.viewport(2, 0, 3, 2);
+        assert_eq!(
+            map.gen_view(&view),
+            vec![0, 0, 0, 0, 0, 0, TILE_ROBOT, ORIENT_UP, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn translate_moves_into_air_only() {
+        let mut map = TileMap::new(5, 2);
+        map.set_tile(4, 1, robot());
+        assert!(map.translate_tile(4, 1, 4, 0));
+        assert!(map.is_air(4, 1));
+        assert_eq!(map.get(4, 0), Some(&robot()));
+
+        map.set_tile(
+            3,
+            0,
+            Tile {
+                id: TILE_WALL,
+                orient: 0,
+            },
+        );
+        assert!(!map.translate_tile(4, 0, 3, 0));
+        assert!(!map.translate_tile(4, 0, 5, 0));
+        assert_eq!(map.get(4, 0), Some(&robot()));
+    }
+
+    #[test]
+    fn neighbours_stop_at_edges() {
+        let map = TileMap::new(3, 2);
+        assert_eq!(map.neighbour(0, 0, ORIENT_UP), None);
+        assert_eq!(map.neighbour(0, 0, ORIENT_LEFT), None);
+        assert_eq!(map.neighbour(2, 1, ORIENT_RIGHT), None);
+        assert_eq!(map.neighbour(2, 1, ORIENT_DOWN), None);
+        assert_eq!(map.neighbour(0, 0, ORIENT_RIGHT), Some((1, 0)));
+        assert_eq!(map.neighbour(0, 0, ORIENT_DOWN), Some((0, 1)));
+    }
+
+    #[test]
+    fn changes_are_tracked_once() {
+        let mut map = TileMap::new(3, 3);
+        map.set_tile(1, 1, robot());
+        map.rotate_tile(1, 1, ORIENT_DOWN);
+        assert!(map.translate_tile(1, 1, 1, 2));
+        assert!(!map.translate_tile(1, 2, 1, 3));
+        assert_eq!(map.take_changes(), vec![(1, 1), (1, 2)]);
+        assert!(map.take_changes().is_empty());
+    }
+
+    #[test]
+    fn viewport_contains_only_its_tiles() {
+        let map = TileMap::new(10, 10);
+        let view = map.viewport(2, 3, 4, 1);
+        assert!(view.contains(2, 3));
+        assert!(view.contains(5, 3));
+        assert!(!view.contains(6, 3));
+        assert!(!view.contains(2, 4));
+        assert!(!view.contains(1, 3));
+    }
+
+    #[test]
+    fn find_air_scans_rows() {
+        let mut map = TileMap::new(4, 3);
+        map.generate_box_corner();
+        assert_eq!(map.find_air(), Some((1, 1)));
+    }
+}
@@ -1,5 +1,7 @@
 let textArea = document.getElementById("editor");
 let convertB = document.getElementById("cnvt_button");
+let viewX = 0;
+let viewY = 0;
 let webSock = new WebSocket("ws://localhost:8080");
 
 
@@ -7,10 +9,63 @@ resources.onReady(renderMap);
 
 webSock.onopen = function (event) {
 	convertB.addEventListener("click", onConvert);
+	let message = {t: 'v', d: {x: viewX, y: viewY, w: Math.ceil(currentMap._width), h: Math.ceil(currentMap._height)}};
+	webSock.send(JSON.stringify(message));
 }
 
+webSock.binaryType = 'arraybuffer';
+
 webSock.onmessage = function (event) {
-	console.log(event.data);
+	if (event.data instanceof ArrayBuffer) {
+		onFrame(new DataView(event.data));
+	} else {
+		console.log(event.data);
+	}
+}
+
+// Map frames, see backend/src/frame.rs for the layout
+const FRAME_VERSION = 1;
+const FRAME_MAP_VIEW = 0;
+const FRAME_MAP_UPDATE = 1;
+const FLAG_RLE = 1;
+const HEADER_LEN = 16;
+
+function onFrame(frame) {
+	if (frame.getUint8(0) !== FRAME_VERSION) {
+		console.log('Unknown frame version ' + frame.getUint8(0));
+		return;
+	}
+	let type = frame.getUint8(1);
+	let flags = frame.getUint8(2);
+	let w = frame.getUint16(12);
+	let h = frame.getUint16(14);
+	let offset = HEADER_LEN;
+	if (type === FRAME_MAP_VIEW) {
+		// Every tile in the viewport, one column after another
+		let tile = 0;
+		while (offset < frame.byteLength && tile < w * h) {
+			let count = 1;
+			if (flags & FLAG_RLE) {
+				count = frame.getUint8(offset);
+				offset += 1;
+			}
+			let id = frame.getUint8(offset);
+			let orient = frame.getUint8(offset + 1);
+			offset += 2;
+			for (var i = 0; i < count; i++, tile++) {
+				setTile(Math.floor(tile / h), tile % h, id, orient);
+			}
+		}
+		renderMap();
+	} else if (type === FRAME_MAP_UPDATE) {
+		// Only the tiles that changed, relative to the viewport
+		let count = frame.getUint16(offset);
+		offset += 2;
+		for (var i = 0; i < count; i++, offset += 6) {
+			setTile(frame.getUint16(offset), frame.getUint16(offset + 2), frame.getUint8(offset + 4), frame.getUint8(offset + 5));
+		}
+		renderMap();
+	}
 }
 
 function onConvert() {
@@ -20,3 +75,9 @@ function onConvert() {
 	let message = {t: 'u', d: text};
 	webSock.send(JSON.stringify(message));
 }
+
+function setTile(x, y, id, orient) {
+	if (currentMap.map[x] !== undefined && currentMap.map[x][y] !== undefined) {
+		currentMap.map[x][y] = new Tile(id, orient);
+	}
+}
@@ -131,7 +131,7 @@ class MapDisp {
 }
 
 currentMap = new MapDisp(canvas.width/32, canvas.height/32);
-currentMap.makeMap();
+currentMap.initializeMap();
 
 function renderMap() {
 	if (resources.isReady()) {
@@ -143,7 +143,9 @@ function renderMap() {
 				ctx.drawImage(gridSpr, x*32, y*32, 32, 32);
 				if (id != 0) {
 					let tileSpr = resources.get('/res/tile' + id.toString() + '_' + orient.toString() + '.png');
-					ctx.drawImage(tileSpr, x*32, y*32, 32, 32);
+					if (tileSpr) {
+						ctx.drawImage(tileSpr, x*32, y*32, 32, 32);
+					}
 				}
 			}
 		}